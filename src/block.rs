@@ -9,6 +9,8 @@ use sha2::{Digest, Sha256};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub const TARGET_BITS: u32 = 16; // 保持较低难度
+/// How far, in milliseconds, a block's timestamp may be ahead of our clock
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
@@ -21,7 +23,6 @@ pub struct Block {
     pub difficulty: u32,
 }
 
-#[allow(dead_code)]
 impl Block {
     pub fn new_block(
         transactions: Vec<Transaction>,
@@ -57,7 +58,7 @@ impl Block {
         Ok(block)
     }
 
-    fn run_proof_of_work(&mut self) -> Result<()> {
+    pub fn run_proof_of_work(&mut self) -> Result<()> {
         info!(
             "Mining block: height={}, difficulty={}",
             self.height, self.difficulty
@@ -169,7 +170,7 @@ impl Block {
         let is_valid = hash_bytes.as_slice() < target.as_slice();
         
         // 添加调试输出
-        if self.nonce % 1_000_000 == 0 {
+        if self.nonce.is_multiple_of(1_000_000) {
             info!(
                 "Validation check: nonce={}, hash={}, target={}, valid={}",
                 self.nonce,
@@ -188,8 +189,8 @@ impl Block {
         let zero_bits = self.difficulty % 8;
         
         // 设置前导零字节
-        for i in 0..zero_bytes.min(32) {
-            target[i] = 0;
+        for byte in target.iter_mut().take(zero_bytes.min(32)) {
+            *byte = 0;
         }
         
         // 设置部分零的字节
@@ -201,15 +202,31 @@ impl Block {
         target
     }
     
-    pub fn validate(&self, prev_block: &Block) -> Result<bool> {
-        let calculated_hash = self.calculate_hash()?;
-        if calculated_hash != self.hash {
-            return Err(anyhow!("Block hash mismatch"));
+    /// Recomputes the hash of the header, which commits to the merkle root
+    /// of the transaction ids, and checks it against the target for the
+    /// block's difficulty
+    pub fn check_proof_of_work(&self) -> Result<()> {
+        if self.calculate_hash()? != self.hash {
+            return Err(anyhow!("Block hash does not match its header"));
         }
-        
         if !self.is_valid_proof(&self.hash) {
             return Err(anyhow!("Invalid proof-of-work"));
         }
+        Ok(())
+    }
+    
+    /// Checks that `self` follows `prev_block`, or is a genesis block when
+    /// `prev_block` is `None`
+    pub fn check_link(&self, prev_block: Option<&Block>) -> Result<()> {
+        let prev_block = match prev_block {
+            Some(prev_block) => prev_block,
+            None => {
+                if !self.prev_block_hash.is_empty() || self.height != 0 {
+                    return Err(anyhow!("Genesis block must have height 0 and no parent"));
+                }
+                return Ok(());
+            }
+        };
         
         if self.prev_block_hash != prev_block.hash {
             return Err(anyhow!("Previous block hash mismatch"));
@@ -223,7 +240,28 @@ impl Block {
             return Err(anyhow!("Invalid block timestamp"));
         }
         
-        Ok(true)
+        Ok(())
+    }
+    
+    /// Rejects a timestamp more than `MAX_FUTURE_BLOCK_TIME` ahead of our
+    /// clock, which would let a miner unlock time-locked coins early
+    pub fn check_timestamp(&self) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        if self.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(anyhow!("Block timestamp {} is too far in the future", self.timestamp));
+        }
+        Ok(())
+    }
+    
+    /// Checks every transaction against its id, so that the merkle root
+    /// the header commits to covers the transactions themselves
+    pub fn check_merkle_root(&self) -> Result<()> {
+        for tx in &self.transactions {
+            if tx.hash()? != tx.id {
+                return Err(anyhow!("Transaction {} does not match its id", tx.id));
+            }
+        }
+        Ok(())
     }
     
     pub fn calculate_hash(&self) -> Result<String> {
@@ -1,42 +1,59 @@
 use crate::block::Block;
-use crate::transaction::Transaction;
-use crate::transaction::TXOutputs;
+use crate::script::MAX_DATA_SIZE;
+use crate::store::{ChainBatch, ChainStore, MemoryStore, SledStore, SpentOutput, TxLocation};
+use crate::transaction::{sum_values, RelativeLock, TXOutput, TXOutputs, Transaction, MAX_MONEY, SUBSIDY};
 use anyhow::{anyhow, Result};
-use bincode::{deserialize, serialize};
-use sled::Db;
-use std::collections::HashMap;
-use std::sync::Mutex;
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use crate::block::TARGET_BITS;
 
 const GENESIS_COINBASE_DATA: &str = "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+/// Directory of the chain database below the data directory
+const BLOCKS_DIR: &str = "blocks";
 
-pub struct Blockchain {
-    tip: Mutex<String>,
-    db: Db,
+/// Checks run by `verify_chain` at each level; every level includes the
+/// ones below it, and level 0 checks proof-of-work, linkage and that
+/// timestamps are not too far in the future
+pub const VERIFY_MERKLE: u32 = 1;
+pub const VERIFY_DIFFICULTY: u32 = 2;
+pub const VERIFY_TRANSACTIONS: u32 = 3;
+pub const VERIFY_UTXO_SET: u32 = 4;
+/// Number of blocks whose median timestamp lock times are checked against
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// The first block rejected by `verify_chain`
+#[derive(Debug)]
+pub struct VerifyFailure {
+    pub height: i32,
+    pub hash: String,
+    pub reason: String,
 }
 
-impl Clone for Blockchain {
-    fn clone(&self) -> Self {
-        let db = self.db.clone();
-        let tip = self.tip.lock().unwrap().clone();
-        Blockchain {
-            tip: Mutex::new(tip),
-            db,
-        }
-    }
+/// The active chain on top of a `ChainStore`. Clones share the store and
+/// the tip, so a block connected through one clone is seen by all of them.
+#[derive(Clone)]
+pub struct Blockchain {
+    tip: Arc<Mutex<String>>,
+    store: Arc<dyn ChainStore>,
 }
 
 impl Blockchain {
-    pub fn create_blockchain(address: &str) -> Result<Self> {
-        let db = sled::open("data/blocks")?;
-        
-        if db.contains_key("l")? {
+    pub fn create_blockchain(data_dir: &Path, address: &str, txindex: bool) -> Result<Self> {
+        let store = SledStore::open(data_dir.join(BLOCKS_DIR))?;
+        Self::create_with_store(Arc::new(store), address, txindex)
+    }
+    
+    pub fn create_with_store(store: Arc<dyn ChainStore>, address: &str, txindex: bool) -> Result<Self> {
+        if store.get_tip()?.is_some() {
             return Err(anyhow!("Blockchain already exists"));
         }
         
         let cbtx = Transaction::new_coinbase(
             address.to_string(),
             GENESIS_COINBASE_DATA.to_string(),
+            0,
         )?;
         
         let genesis = Block::new_block(
@@ -46,29 +63,90 @@ impl Blockchain {
             TARGET_BITS,
         )?;
         
-        let genesis_hash = genesis.hash.clone();
-        db.insert(genesis_hash.as_bytes(), serialize(&genesis)?)?;
-        db.insert("l", genesis_hash.as_bytes())?;
+        Self::create_from_genesis_with_store(store, &genesis, txindex)
+    }
+    
+    /// Starts a new chain from an existing genesis block, such as the first
+    /// block of an export file
+    pub fn create_from_genesis(data_dir: &Path, genesis: &Block, txindex: bool) -> Result<Self> {
+        let store = SledStore::open(data_dir.join(BLOCKS_DIR))?;
+        Self::create_from_genesis_with_store(Arc::new(store), genesis, txindex)
+    }
+    
+    pub fn create_from_genesis_with_store(store: Arc<dyn ChainStore>, genesis: &Block, txindex: bool) -> Result<Self> {
+        if store.get_tip()?.is_some() {
+            return Err(anyhow!("Blockchain already exists"));
+        }
         
         let bc = Blockchain {
-            tip: Mutex::new(genesis_hash),
-            db,
+            tip: Arc::new(Mutex::new(String::new())),
+            store,
+        };
+        bc.check_block(genesis, None, VERIFY_TRANSACTIONS)?;
+        // The index setting is written with the genesis block, so a
+        // failed creation leaves nothing behind
+        let batch = ChainBatch {
+            tx_index_enabled: Some(txindex),
+            ..Default::default()
         };
+        bc.connect_block_with(genesis, txindex, batch)?;
         
         Ok(bc)
     }
     
-    pub fn open() -> Result<Self> {
-        let db = sled::open("data/blocks")?;
-        let tip = match db.get("l")? {
-            Some(t) => String::from_utf8(t.to_vec())?,
-            None => return Err(anyhow!("Blockchain not found. Create one first")),
+    /// Returns whether a chain has been created in `data_dir`
+    pub fn exists(data_dir: &Path) -> Result<bool> {
+        let store = SledStore::open(data_dir.join(BLOCKS_DIR))?;
+        Ok(store.get_tip()?.is_some())
+    }
+    
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        let store = SledStore::open(data_dir.join(BLOCKS_DIR))?;
+        Self::open_with_store(Arc::new(store))
+    }
+    
+    pub fn open_with_store(store: Arc<dyn ChainStore>) -> Result<Self> {
+        let tip = store.get_tip()?
+            .ok_or_else(|| anyhow!("Blockchain not found. Create one first"))?;
+        
+        let bc = Blockchain {
+            tip: Arc::new(Mutex::new(tip)),
+            store,
         };
+        bc.check_consistency()?;
         
-        Ok(Blockchain {
-            tip: Mutex::new(tip),
-            db,
-        })
+        Ok(bc)
+    }
+    
+    /// Verifies that the tip, the indexes and the UTXO set agree with each
+    /// other, rebuilding whatever was left behind by an interrupted write
+    pub fn check_consistency(&self) -> Result<()> {
+        let tip = self.tip.lock().unwrap().clone();
+        let tip_block = self.get_block(&tip)
+            .map_err(|e| anyhow!("Cannot read tip block {}: {}. Chains written in an older format must be recreated", tip, e))?;
+        
+        let mut index_ok = self.store.get_block_hash(tip_block.height)?.as_deref() == Some(tip.as_str())
+            && self.store.get_block_hash(tip_block.height + 1)?.is_none();
+        if index_ok && self.store.tx_index_enabled()? {
+            for tx in &tip_block.transactions {
+                if self.store.get_tx_location(&tx.id)?.is_none() {
+                    index_ok = false;
+                    break;
+                }
+            }
+        }
+        if !index_ok {
+            warn!("Block indexes do not match tip {}, rebuilding", tip);
+            self.rebuild_indexes()?;
+        }
+        
+        if self.store.get_utxo_tip()?.as_deref() != Some(tip.as_str()) {
+            warn!("UTXO set does not match tip {}, rebuilding", tip);
+            let count = self.rebuild_utxo_set()?;
+            info!("UTXO set rebuilt with {} transactions", count);
+        }
+        
+        Ok(())
     }
     
     pub fn add_block(&mut self, transactions: Vec<Transaction>) -> Result<()> {
@@ -76,15 +154,540 @@ impl Blockchain {
         let new_height = self.get_best_height()? + 1;
         
         let block = Block::new_block(transactions, last_hash, new_height, TARGET_BITS)?;
-        let block_hash = block.hash.clone();
+        self.check_transactions(&block)?;
+        self.connect_block(&block)
+    }
+    
+    /// Fully validates `block` and connects it on top of the current tip
+    pub fn accept_block(&self, block: &Block) -> Result<()> {
+        let tip = self.tip.lock().unwrap().clone();
+        let tip_block = self.get_block(&tip)?;
+        self.check_block(block, Some(&tip_block), VERIFY_TRANSACTIONS)?;
+        self.connect_block(block)
+    }
+    
+    /// Accepts a block that is either already on the active chain or
+    /// extends it. Returns `false` for blocks that were already known.
+    pub fn import_block(&self, block: &Block) -> Result<bool> {
+        if block.height <= self.get_best_height()? {
+            let existing = self.get_block_hash(block.height)?;
+            if existing != block.hash {
+                return Err(anyhow!("Conflicts with block {} on the active chain", existing));
+            }
+            return Ok(false);
+        }
+        
+        self.accept_block(block)?;
+        Ok(true)
+    }
+    
+    /// Takes a block whose parent is stored. A block extending the tip is
+    /// connected; one on another branch is stored, and once that branch is
+    /// longer than the active chain the chain switches over to it. Returns
+    /// the blocks disconnected, tip first, and those connected in order.
+    pub fn process_block(&self, block: &Block) -> Result<(Vec<Block>, Vec<Block>)> {
+        let tip = self.tip.lock().unwrap().clone();
+        if block.prev_block_hash == tip {
+            self.accept_block(block)?;
+            return Ok((Vec::new(), vec![block.clone()]));
+        }
+    
+        let parent = self.get_block(&block.prev_block_hash)?;
+        self.check_block(block, Some(&parent), VERIFY_DIFFICULTY)?;
+        self.store.commit(ChainBatch {
+            blocks: vec![block.clone()],
+            ..Default::default()
+        })?;
+    
+        let best_height = self.get_best_height()?;
+        if block.height <= best_height {
+            info!("Stored block {} on a side branch at height {}", block.hash, block.height);
+            return Ok((Vec::new(), Vec::new()));
+        }
+    
+        // Walk the branch back to where it leaves the active chain
+        let mut branch = vec![block.clone()];
+        let mut fork = parent;
+        while self.store.get_block_hash(fork.height)?.as_deref() != Some(fork.hash.as_str()) {
+            let prev = self.get_block(&fork.prev_block_hash)?;
+            branch.push(fork);
+            fork = prev;
+        }
+        branch.reverse();
+        info!(
+            "Reorganizing from height {} onto branch ending at {} from fork point {}",
+            best_height, block.hash, fork.hash
+        );
+    
+        let mut disconnected = Vec::new();
+        for _ in fork.height..best_height {
+            disconnected.push(self.disconnect_tip()?);
+        }
+        for (connected, branch_block) in branch.iter().enumerate() {
+            if let Err(e) = self.accept_block(branch_block) {
+                // Go back to the old chain
+                for _ in 0..connected {
+                    self.disconnect_tip()?;
+                }
+                for old in disconnected.iter().rev() {
+                    self.connect_block(old)?;
+                }
+                return Err(anyhow!("Branch block {} is invalid: {}", branch_block.hash, e));
+            }
+        }
+    
+        Ok((disconnected, branch))
+    }
+    
+    /// Stores `block` on top of the current tip, updating the indexes and
+    /// the UTXO set in one atomic write
+    fn connect_block(&self, block: &Block) -> Result<()> {
+        let txindex = self.store.tx_index_enabled()?;
+        self.connect_block_with(block, txindex, ChainBatch::default())
+    }
+    
+    /// Connects `block` in the same write as the rest of `batch`, adding
+    /// its transactions to the index if `txindex`
+    fn connect_block_with(&self, block: &Block, txindex: bool, mut batch: ChainBatch) -> Result<()> {
+        let mut tip = self.tip.lock().unwrap();
+        if block.prev_block_hash != *tip {
+            return Err(anyhow!("Block {} does not extend the current tip", block.hash));
+        }
+        
+        batch.tip = Some(block.hash.clone());
+        batch.utxo_tip = Some(block.hash.clone());
+        batch.heights.push((block.height, Some(block.hash.clone())));
+        
+        if txindex {
+            for (position, tx) in block.transactions.iter().enumerate() {
+                let location = TxLocation {
+                    block_hash: block.hash.clone(),
+                    position: position as u32,
+                };
+                batch.tx_index.push((tx.id.clone(), Some(location)));
+            }
+        }
+        
+        // Apply the block to a local view of the touched UTXO entries so
+        // that outputs created and spent within the block are handled
+        let mut changed: HashMap<String, TXOutputs> = HashMap::new();
+        let mut spent = Vec::new();
+        for tx in &block.transactions {
+            if !tx.is_coinbase() {
+                for input in &tx.vin {
+                    if !changed.contains_key(&input.txid) {
+                        let outs = self.store.get_utxos(&input.txid)?.unwrap_or_default();
+                        changed.insert(input.txid.clone(), outs);
+                    }
+                    let (output, height) = changed.get_mut(&input.txid)
+                        .and_then(|outs| Some((outs.outputs.remove(&input.vout)?, outs.height)))
+                        .ok_or_else(|| anyhow!("Transaction {} spends missing output {}:{}", tx.id, input.txid, input.vout))?;
+                    spent.push(SpentOutput {
+                        txid: input.txid.clone(),
+                        vout: input.vout,
+                        output,
+                        height,
+                    });
+                }
+            }
+            
+            let outs = changed.entry(tx.id.clone()).or_default();
+            outs.height = block.height;
+            for (idx, output) in tx.vout.iter().enumerate() {
+                if !output.script_pubkey.is_unspendable() {
+                    outs.outputs.insert(idx as i32, output.clone());
+                }
+            }
+        }
+        
+        batch.utxos = changed.into_iter()
+            .map(|(txid, outs)| (txid, Some(outs).filter(|outs| !outs.outputs.is_empty())))
+            .collect();
+        batch.undo.push((block.hash.clone(), Some(spent)));
+        batch.blocks.push(block.clone());
+        
+        self.store.commit(batch)?;
+        *tip = block.hash.clone();
+        
+        Ok(())
+    }
+    
+    /// Removes the tip block from the active chain, restoring the outputs
+    /// it spent, and returns it. The block itself stays in the store.
+    pub fn disconnect_tip(&self) -> Result<Block> {
+        let mut tip = self.tip.lock().unwrap();
+        let block = self.get_block(&tip)?;
+        if block.prev_block_hash.is_empty() {
+            return Err(anyhow!("Cannot disconnect the genesis block"));
+        }
+        
+        let spent = self.store.get_undo(&block.hash)?
+            .ok_or_else(|| anyhow!("Undo data for block {} is missing", block.hash))?;
+        
+        let mut batch = ChainBatch {
+            tip: Some(block.prev_block_hash.clone()),
+            utxo_tip: Some(block.prev_block_hash.clone()),
+            heights: vec![(block.height, None)],
+            undo: vec![(block.hash.clone(), None)],
+            ..Default::default()
+        };
+        
+        let txindex = self.store.tx_index_enabled()?;
+        let mut changed: HashMap<String, TXOutputs> = HashMap::new();
+        for tx in &block.transactions {
+            if txindex {
+                batch.tx_index.push((tx.id.clone(), None));
+            }
+            changed.insert(tx.id.clone(), TXOutputs::default());
+        }
+        for spent_output in spent {
+            if !changed.contains_key(&spent_output.txid) {
+                let outs = self.store.get_utxos(&spent_output.txid)?.unwrap_or_default();
+                changed.insert(spent_output.txid.clone(), outs);
+            }
+            if let Some(outs) = changed.get_mut(&spent_output.txid) {
+                outs.height = spent_output.height;
+                outs.outputs.insert(spent_output.vout, spent_output.output);
+            }
+        }
+        
+        batch.utxos = changed.into_iter()
+            .map(|(txid, outs)| (txid, Some(outs).filter(|outs| !outs.outputs.is_empty())))
+            .collect();
+        
+        self.store.commit(batch)?;
+        *tip = block.prev_block_hash.clone();
+        
+        Ok(block)
+    }
+    
+    /// Rebuilds the height index (and the transaction index if enabled)
+    /// by walking the chain from the tip
+    pub fn rebuild_indexes(&self) -> Result<()> {
+        let txindex = self.store.tx_index_enabled()?;
+        let mut batch = ChainBatch {
+            clear_indexes: true,
+            ..Default::default()
+        };
+        
+        for block in self.iter() {
+            let block = block?;
+            batch.heights.push((block.height, Some(block.hash.clone())));
+            if txindex {
+                for (position, tx) in block.transactions.iter().enumerate() {
+                    let location = TxLocation {
+                        block_hash: block.hash.clone(),
+                        position: position as u32,
+                    };
+                    batch.tx_index.push((tx.id.clone(), Some(location)));
+                }
+            }
+        }
+        
+        self.store.commit(batch)
+    }
+    
+    /// Recomputes the UTXO set from the blocks on the active chain
+    pub fn rebuild_utxo_set(&self) -> Result<usize> {
+        let tip = self.tip.lock().unwrap().clone();
+        let utxos = self.find_utxo()?;
+        let len = utxos.len();
+        
+        let batch = ChainBatch {
+            utxo_tip: Some(tip),
+            utxos: utxos.into_iter().map(|(txid, outs)| (txid, Some(outs))).collect(),
+            clear_utxos: true,
+            ..Default::default()
+        };
+        
+        self.store.commit(batch)?;
+        Ok(len)
+    }
+    
+    /// Walks the chain from genesis to tip re-running the checks enabled at
+    /// `level`. From `VERIFY_TRANSACTIONS` on, the UTXO set is rebuilt in
+    /// memory along the way; `VERIFY_UTXO_SET` compares it with the stored one.
+    pub fn verify_chain(&self, level: u32) -> Result<Option<VerifyFailure>> {
+        if level > VERIFY_UTXO_SET {
+            return Err(anyhow!("Unknown verification level {}, the highest is {}", level, VERIFY_UTXO_SET));
+        }
+        let tip_height = self.get_best_height()?;
+        let replay = Blockchain {
+            tip: Arc::new(Mutex::new(String::new())),
+            store: Arc::new(MemoryStore::new()),
+        };
+        
+        let mut prev: Option<Block> = None;
+        for height in 0..=tip_height {
+            let fail = |hash: &str, reason: String| Ok(Some(VerifyFailure {
+                height,
+                hash: hash.to_string(),
+                reason,
+            }));
+            
+            let block = match self.get_block_by_height(height) {
+                Ok(block) => block,
+                Err(e) => return fail("", e.to_string()),
+            };
+            if let Err(e) = replay.check_block(&block, prev.as_ref(), level) {
+                return fail(&block.hash, e.to_string());
+            }
+            if level >= VERIFY_TRANSACTIONS {
+                if let Err(e) = replay.connect_block(&block) {
+                    return fail(&block.hash, e.to_string());
+                }
+            }
+            prev = Some(block);
+        }
+        
+        if level >= VERIFY_UTXO_SET {
+            let tip = self.tip.lock().unwrap().clone();
+            let stored = self.utxo_iter().collect::<Result<BTreeMap<_, _>>>()?;
+            let rebuilt = replay.utxo_iter().collect::<Result<BTreeMap<_, _>>>()?;
+            
+            let mut txids: Vec<&String> = stored.keys().chain(rebuilt.keys()).collect();
+            txids.sort();
+            txids.dedup();
+            for txid in txids {
+                let reason = match (stored.get(txid), rebuilt.get(txid)) {
+                    (Some(a), Some(b)) if a == b => continue,
+                    (Some(_), Some(_)) => "has different outputs",
+                    (Some(_), None) => "is not in the rebuilt set",
+                    (None, _) => "is missing from the stored set",
+                };
+                return Ok(Some(VerifyFailure {
+                    height: tip_height,
+                    hash: tip,
+                    reason: format!("UTXO set mismatch: transaction {} {}", txid, reason),
+                }));
+            }
+        }
+        
+        Ok(None)
+    }
+    
+    /// Checks `block` as the successor of `prev` (genesis when `None`),
+    /// with transactions validated against this chain's UTXO set
+    fn check_block(&self, block: &Block, prev: Option<&Block>, level: u32) -> Result<()> {
+        block.check_proof_of_work()?;
+        block.check_link(prev)?;
+        block.check_timestamp()?;
+        
+        if level >= VERIFY_MERKLE {
+            block.check_merkle_root()?;
+        }
+        
+        if level >= VERIFY_DIFFICULTY && block.difficulty != TARGET_BITS {
+            return Err(anyhow!(
+                "Unexpected difficulty {} (expected {})",
+                block.difficulty, TARGET_BITS
+            ));
+        }
+        
+        if level >= VERIFY_TRANSACTIONS {
+            self.check_transactions(block)?;
+        }
         
-        self.db.insert(block_hash.as_bytes(), serialize(&block)?)?;
-        self.db.insert("l", block_hash.as_bytes())?;
-        *self.tip.lock().unwrap() = block_hash;
+        Ok(())
+    }
+    
+    /// Checks coinbase placement and value, that every input spends an
+    /// unspent output at most once, lock times, that inputs cover outputs,
+    /// and the input signatures
+    fn check_transactions(&self, block: &Block) -> Result<()> {
+        let time = if block.prev_block_hash.is_empty() {
+            block_time(block)
+        } else {
+            self.median_time_past(&self.get_block(&block.prev_block_hash)?)?
+        };
+        let mut created: HashMap<(String, i32), TXOutput> = HashMap::new();
+        let mut spent: HashSet<(String, i32)> = HashSet::new();
+        let mut fees = 0;
+        let mut coinbase_value = 0;
+        
+        for (position, tx) in block.transactions.iter().enumerate() {
+            let output_value = check_outputs(tx)?;
+            
+            if tx.is_coinbase() {
+                if position != 0 {
+                    return Err(anyhow!("Coinbase transaction {} is not first in the block", tx.id));
+                }
+                coinbase_value = output_value;
+            } else {
+                let mut prev_outputs = Vec::new();
+                let mut coin_heights = Vec::new();
+                for input in &tx.vin {
+                    let key = (input.txid.clone(), input.vout);
+                    if !spent.insert(key.clone()) {
+                        return Err(anyhow!("Output {}:{} is spent twice in the block", input.txid, input.vout));
+                    }
+                    let (prev_output, coin_height) = match created.get(&key) {
+                        Some(output) => (output.clone(), block.height),
+                        None => self.find_coin(&input.txid, input.vout)?
+                            .ok_or_else(|| anyhow!("Transaction {} spends missing output {}:{}", tx.id, input.txid, input.vout))?,
+                    };
+                    prev_outputs.push(prev_output);
+                    coin_heights.push(coin_height);
+                }
+                
+                let fee = self.check_spend(tx, &prev_outputs, &coin_heights, block.height, time)?;
+                fees = sum_values([fees, fee])
+                    .map_err(|e| anyhow!("Fees of block {}: {}", block.hash, e))?;
+            }
+            
+            for (idx, output) in tx.vout.iter().enumerate() {
+                if !output.script_pubkey.is_unspendable() {
+                    created.insert((tx.id.clone(), idx as i32), output.clone());
+                }
+            }
+        }
+        
+        let reward = sum_values([SUBSIDY, fees])?;
+        if coinbase_value > reward {
+            return Err(anyhow!(
+                "Coinbase pays {} but subsidy plus fees is only {}",
+                coinbase_value, reward
+            ));
+        }
         
         Ok(())
     }
     
+    /// Checks a transaction outside of a block for inclusion in the next
+    /// block, as the mempool does. Inputs are looked up in the UTXO set,
+    /// then with `unconfirmed` among outputs of transactions that are not
+    /// in a block yet. Returns its fee.
+    pub fn check_transaction(&self, tx: &Transaction, unconfirmed: &dyn Fn(&str, i32) -> Option<TXOutput>) -> Result<i32> {
+        if tx.is_coinbase() {
+            return Err(anyhow!("Coinbase transaction {} is only valid in a block", tx.id));
+        }
+        if tx.vin.is_empty() || tx.vout.is_empty() {
+            return Err(anyhow!("Transaction {} has no inputs or no outputs", tx.id));
+        }
+        if tx.hash()? != tx.id {
+            return Err(anyhow!("Transaction id {} does not match its contents", tx.id));
+        }
+        check_outputs(tx)?;
+        
+        // Unconfirmed outputs count as created in the next block
+        let height = self.get_best_height()? + 1;
+        let mut spent = HashSet::new();
+        let mut prev_outputs = Vec::new();
+        let mut coin_heights = Vec::new();
+        for input in &tx.vin {
+            if !spent.insert((input.txid.clone(), input.vout)) {
+                return Err(anyhow!("Transaction {} spends {}:{} twice", tx.id, input.txid, input.vout));
+            }
+            let coin = match self.find_coin(&input.txid, input.vout)? {
+                Some(coin) => Some(coin),
+                None => unconfirmed(&input.txid, input.vout).map(|output| (output, height)),
+            };
+            let (prev_output, coin_height) = coin
+                .ok_or_else(|| anyhow!("Transaction {} spends missing output {}:{}", tx.id, input.txid, input.vout))?;
+            prev_outputs.push(prev_output);
+            coin_heights.push(coin_height);
+        }
+        
+        let time = self.median_time_past(&self.get_block_by_height(height - 1)?)?;
+        self.check_spend(tx, &prev_outputs, &coin_heights, height, time)
+    }
+    
+    /// Median timestamp, in seconds, of `block` and the blocks before it
+    /// in a span of `MEDIAN_TIME_SPAN`. Unlike a block's own timestamp,
+    /// its miner cannot move it ahead.
+    pub fn median_time_past(&self, block: &Block) -> Result<u64> {
+        let mut times = vec![block_time(block)];
+        let mut prev_hash = block.prev_block_hash.clone();
+        while times.len() < MEDIAN_TIME_SPAN && !prev_hash.is_empty() {
+            let prev = self.get_block(&prev_hash)?;
+            times.push(block_time(&prev));
+            prev_hash = prev.prev_block_hash;
+        }
+        times.sort_unstable();
+        Ok(times[times.len() / 2])
+    }
+    
+    /// Checks that `tx` may be included in a block at `height` whose
+    /// parent has median time past `time`, that its inputs cover its
+    /// outputs and satisfy their locking scripts. `coin_heights[i]` is the
+    /// height the output spent by input `i` was created at. Relative time
+    /// locks count from the median time past of the block before that.
+    /// Returns the fee.
+    fn check_spend(&self, tx: &Transaction, prev_outputs: &[TXOutput], coin_heights: &[i32], height: i32, time: u64) -> Result<i32> {
+        if !tx.is_final(height, time) {
+            return Err(anyhow!("Transaction {} is locked until {}", tx.id, tx.lock_time));
+        }
+        if tx.version >= 2 {
+            for (input, &coin_height) in tx.vin.iter().zip(coin_heights) {
+                match input.relative_lock() {
+                    Some(RelativeLock::Blocks(blocks)) if coin_height as i64 + blocks as i64 > height as i64 => {
+                        return Err(anyhow!(
+                            "Input {}:{} of {} is locked until height {}",
+                            input.txid, input.vout, tx.id, coin_height as i64 + blocks as i64
+                        ));
+                    }
+                    Some(RelativeLock::Seconds(seconds)) => {
+                        let coin_time = if coin_height >= height {
+                            time
+                        } else {
+                            self.median_time_past(&self.get_block_by_height((coin_height - 1).max(0))?)?
+                        };
+                        if coin_time + seconds > time {
+                            return Err(anyhow!(
+                                "Input {}:{} of {} is locked until time {}",
+                                input.txid, input.vout, tx.id, coin_time + seconds
+                            ));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        
+        let input_value = sum_values(prev_outputs.iter().map(|out| out.value))
+            .map_err(|e| anyhow!("Inputs of transaction {}: {}", tx.id, e))?;
+        let output_value = sum_values(tx.vout.iter().map(|out| out.value))
+            .map_err(|e| anyhow!("Outputs of transaction {}: {}", tx.id, e))?;
+        if input_value < output_value {
+            return Err(anyhow!(
+                "Transaction {} spends {} but only has {} in inputs",
+                tx.id, output_value, input_value
+            ));
+        }
+        tx.verify(prev_outputs)?;
+        Ok(input_value - output_value)
+    }
+    
+    /// Unspent output `txid:vout` with the height it was created at
+    fn find_coin(&self, txid: &str, vout: i32) -> Result<Option<(TXOutput, i32)>> {
+        Ok(self.store.get_utxos(txid)?
+            .and_then(|mut outs| Some((outs.outputs.remove(&vout)?, outs.height))))
+    }
+    
+    /// Turns the transaction index on for an existing chain
+    pub fn enable_tx_index(&mut self) -> Result<()> {
+        self.store.commit(ChainBatch {
+            tx_index_enabled: Some(true),
+            ..Default::default()
+        })
+    }
+    
+    pub fn has_tx_index(&self) -> Result<bool> {
+        self.store.tx_index_enabled()
+    }
+    
+    pub fn get_utxos(&self, txid: &str) -> Result<Option<TXOutputs>> {
+        self.store.get_utxos(txid)
+    }
+    
+    pub fn utxo_iter(&self) -> Box<dyn Iterator<Item = Result<(String, TXOutputs)>> + '_> {
+        self.store.utxo_iter()
+    }
+    
+    pub fn utxo_count(&self) -> Result<usize> {
+        self.store.utxo_count()
+    }
+    
     pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<()> {
         self.add_block(transactions)
     }
@@ -96,44 +699,61 @@ impl Blockchain {
     }
     
     pub fn get_block_count(&self) -> Result<usize> {
-        Ok(self.db.len() - 1)
+        self.store.height_count()
     }
     
     pub fn get_block(&self, hash: &str) -> Result<Block> {
-        let data = self.db.get(hash)?
-            .ok_or_else(|| anyhow!("Block not found"))?;
-        let block = deserialize(&data)?;
-        Ok(block)
+        self.store.get_block(hash)?
+            .ok_or_else(|| anyhow!("Block not found"))
     }
     
-    pub fn iter(&self) -> BlockchainIter {
-        BlockchainIter {
-            current_hash: self.tip.lock().unwrap().clone(),
-            bc: self,
+    pub fn get_block_hash(&self, height: i32) -> Result<String> {
+        self.store.get_block_hash(height)?
+            .ok_or_else(|| anyhow!("No block at height {}", height))
+    }
+    
+    pub fn get_block_by_height(&self, height: i32) -> Result<Block> {
+        let hash = self.get_block_hash(height)?;
+        self.get_block(&hash)
+    }
+    
+    pub fn get_transaction(&self, txid: &str) -> Result<(Transaction, TxLocation)> {
+        if !self.store.tx_index_enabled()? {
+            return Err(anyhow!("Transaction index is disabled. Run reindex --txindex first"));
+        }
+        let location = self.store.get_tx_location(txid)?
+            .ok_or_else(|| anyhow!("Transaction not found"))?;
+        
+        let mut block = self.get_block(&location.block_hash)?;
+        let position = location.position as usize;
+        if position >= block.transactions.len() {
+            return Err(anyhow!("Transaction index points past the end of block {}", location.block_hash));
         }
+        let tx = block.transactions.swap_remove(position);
+        Ok((tx, location))
     }
     
-    pub fn get_block_hashes(&self) -> Result<Vec<String>> {
-        let mut hashes = Vec::new();
-        for block in self.iter() {
-            let block = block?;
-            hashes.push(block.hash.clone());
+    pub fn iter(&self) -> BlockchainIter<'_> {
+        BlockchainIter {
+            current_hash: self.tip.lock().unwrap().clone(),
+            bc: self,
         }
-        Ok(hashes)
     }
     
     pub fn find_utxo(&self) -> Result<HashMap<String, TXOutputs>> {
-        let mut utxos = HashMap::new();
+        let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
         let mut spent_outputs = HashMap::new();
     
         for block in self.iter() {
             let block = block?;
             for tx in block.transactions {
                 // 收集所有输出
-                for (_vout_idx, output) in tx.vout.iter().enumerate() {
-                    let txid = tx.id.clone();
-                    let outputs = utxos.entry(txid).or_insert(TXOutputs { outputs: vec![] });
-                    outputs.outputs.push(output.clone());
+                let outputs = utxos.entry(tx.id.clone()).or_default();
+                outputs.height = block.height;
+                for (vout_idx, output) in tx.vout.iter().enumerate() {
+                    if !output.script_pubkey.is_unspendable() {
+                        outputs.outputs.insert(vout_idx as i32, output.clone());
+                    }
                 }
     
                 // 如果不是 coinbase 交易，标记已花费的输出
@@ -152,17 +772,13 @@ impl Blockchain {
         for (txid, spent_indices) in spent_outputs {
             if let Some(outputs) = utxos.get_mut(&txid) {
                 for spent_idx in spent_indices {
-                    if (spent_idx as usize) < outputs.outputs.len() {
-                        outputs.outputs[spent_idx as usize].value = -1; // 标记为已花费
-                    }
+                    outputs.outputs.remove(&spent_idx);
                 }
             }
         }
     
-        // 清理已花费的输出
-        for outputs in utxos.values_mut() {
-            outputs.outputs.retain(|output| output.value != -1);
-        }
+        // 清理没有剩余输出的交易
+        utxos.retain(|_, outputs| !outputs.outputs.is_empty());
     
         Ok(utxos)
     }
@@ -189,4 +805,254 @@ impl<'a> Iterator for BlockchainIter<'a> {
             Err(e) => Some(Err(e)),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Rejects negative outputs, values over `MAX_MONEY` and oversized data
+/// outputs. Returns the total output value.
+fn check_outputs(tx: &Transaction) -> Result<i32> {
+    for output in &tx.vout {
+        if output.value < 0 {
+            return Err(anyhow!("Transaction {} has a negative output", tx.id));
+        }
+        if output.value > MAX_MONEY {
+            return Err(anyhow!("Transaction {} has an output over {}", tx.id, MAX_MONEY));
+        }
+        if output.script_pubkey.is_unspendable() && output.script_pubkey.data_payload().is_none_or(|data| data.len() > MAX_DATA_SIZE) {
+            return Err(anyhow!("Transaction {} has a malformed data output or one over {} bytes", tx.id, MAX_DATA_SIZE));
+        }
+    }
+    sum_values(tx.vout.iter().map(|out| out.value))
+        .map_err(|e| anyhow!("Outputs of transaction {}: {}", tx.id, e))
+}
+
+/// Block timestamp in unix seconds, as lock times count them
+fn block_time(block: &Block) -> u64 {
+    (block.timestamp / 1000) as u64
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::script::{Op, Script};
+    use crate::transaction::{TXInput, SEQUENCE_FINAL, SEQUENCE_TYPE_FLAG, TX_VERSION};
+    use crate::utxoset::UTXOSet;
+    use crate::wallets::Wallet;
+
+    pub fn wallet(seed: u8) -> Wallet {
+        Wallet::from_seed([seed; 32])
+    }
+
+    /// A chain in memory whose genesis block pays `miner`
+    pub fn chain(miner: &Wallet) -> Blockchain {
+        Blockchain::create_with_store(Arc::new(MemoryStore::new()), &miner.get_address(), true).unwrap()
+    }
+
+    pub fn coinbase(bc: &Blockchain, height: i32) -> Transaction {
+        bc.get_block_by_height(height).unwrap().transactions[0].clone()
+    }
+
+    /// Transaction moving output `vout` of `prev`, locked to `wallet`, to
+    /// `outputs`
+    pub fn spend(wallet: &Wallet, prev: &Transaction, vout: i32, outputs: Vec<TXOutput>, sequence: u32) -> Transaction {
+        let mut tx = Transaction {
+            id: String::new(),
+            version: TX_VERSION,
+            vin: vec![TXInput {
+                txid: prev.id.clone(),
+                vout,
+                script_sig: Script::default(),
+                sequence,
+            }],
+            vout: outputs,
+            lock_time: 0,
+        };
+        let prev_outputs = [prev.vout[vout as usize].clone()];
+        let signature = tx.input_signature(0, wallet, &prev_outputs).unwrap();
+        tx.vin[0].script_sig = Script(vec![Op::Push(signature), Op::Push(wallet.public_key())]);
+        tx.id = tx.hash().unwrap();
+        tx
+    }
+
+    pub fn pay(to: &Wallet, value: i32) -> TXOutput {
+        TXOutput::new(value, &to.get_address()).unwrap()
+    }
+
+    /// Mines a block on top of `bc`'s tip without connecting it
+    fn block_on(bc: &Blockchain, prev: &Block, miner: &Wallet, txs: Vec<Transaction>) -> Block {
+        let mut transactions = vec![Transaction::new_coinbase(miner.get_address(), String::new(), 0).unwrap()];
+        transactions.extend(txs);
+        let block = Block::new_block(transactions, prev.hash.clone(), prev.height + 1, TARGET_BITS).unwrap();
+        assert!(bc.get_block(&block.hash).is_err());
+        block
+    }
+
+    #[test]
+    fn spends_move_coins_between_addresses() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let mut bc = chain(&alice);
+        let tx = spend(&alice, &coinbase(&bc, 0), 0, vec![pay(&bob, 4), pay(&alice, 5)], SEQUENCE_FINAL);
+        let miner_fee = Transaction::new_coinbase(alice.get_address(), String::new(), 1).unwrap();
+        bc.mine_block(vec![miner_fee, tx]).unwrap();
+
+        let utxo_set = UTXOSet { blockchain: bc.clone() };
+        assert_eq!(utxo_set.get_balance(&bob.get_address()).unwrap(), 4);
+        assert_eq!(utxo_set.get_balance(&alice.get_address()).unwrap(), 5 + SUBSIDY + 1);
+        assert!(bc.verify_chain(VERIFY_UTXO_SET).unwrap().is_none());
+    }
+
+    #[test]
+    fn signatures_cover_the_spent_amounts() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let bc = chain(&alice);
+        let prev = coinbase(&bc, 0);
+        let tx = spend(&alice, &prev, 0, vec![pay(&bob, 4)], SEQUENCE_FINAL);
+        assert!(tx.verify(&prev.vout).is_ok());
+
+        let mut claimed = prev.vout.clone();
+        claimed[0].value = 4;
+        assert!(tx.verify(&claimed).is_err());
+    }
+
+    #[test]
+    fn rejects_double_spends_and_overpaying_coinbase() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let mut bc = chain(&alice);
+        let genesis_coinbase = coinbase(&bc, 0);
+        let first = spend(&alice, &genesis_coinbase, 0, vec![pay(&bob, 10)], SEQUENCE_FINAL);
+        let second = spend(&alice, &genesis_coinbase, 0, vec![pay(&alice, 10)], SEQUENCE_FINAL);
+        let reward = || Transaction::new_coinbase(alice.get_address(), String::new(), 0).unwrap();
+
+        assert!(bc.mine_block(vec![reward(), first.clone(), second.clone()]).is_err());
+        let greedy = Transaction::new_coinbase(alice.get_address(), String::new(), 1).unwrap();
+        assert!(bc.mine_block(vec![greedy, first.clone()]).is_err());
+
+        bc.mine_block(vec![reward(), first]).unwrap();
+        assert!(bc.mine_block(vec![reward(), second]).is_err());
+        assert_eq!(bc.get_best_height().unwrap(), 1);
+    }
+
+    #[test]
+    fn rejects_values_over_max_money() {
+        let alice = wallet(1);
+        let bc = chain(&alice);
+        let coin = coinbase(&bc, 0);
+        let too_large = spend(&alice, &coin, 0, vec![pay(&alice, MAX_MONEY + 1)], SEQUENCE_FINAL);
+        let overflowing = spend(&alice, &coin, 0, vec![pay(&alice, i32::MAX), pay(&alice, i32::MAX)], SEQUENCE_FINAL);
+        let total_too_large = spend(&alice, &coin, 0, vec![pay(&alice, MAX_MONEY), pay(&alice, 1)], SEQUENCE_FINAL);
+
+        for tx in [too_large, overflowing, total_too_large] {
+            assert!(bc.check_transaction(&tx, &|_, _| None).is_err());
+        }
+
+        let coins = UTXOSet { blockchain: bc.clone() }.find_coins(&coin.vout[0].script_pubkey).unwrap();
+        let error = Transaction::new_unsigned(&alice.get_address(), vec![pay(&alice, i32::MAX)], 1, &coins).unwrap_err();
+        assert!(error.to_string().contains("Amount too large"));
+
+        let mut coins = [coins[0].clone(), coins[0].clone()];
+        coins[1].output.value = i32::MAX;
+        let error = Transaction::new_unsigned(&alice.get_address(), vec![pay(&alice, MAX_MONEY)], 0, &coins).unwrap_err();
+        assert!(error.to_string().contains("Amount too large"));
+    }
+
+    #[test]
+    fn checks_the_header_hash_and_verification_level() {
+        let alice = wallet(1);
+        let bc = chain(&alice);
+        let genesis = bc.get_block_by_height(0).unwrap();
+        let mut forged = block_on(&bc, &genesis, &alice, Vec::new());
+        // Keeps the hash, which meets the target, but changes the header
+        forged.nonce += 1;
+        assert!(forged.check_proof_of_work().is_err());
+        assert!(bc.process_block(&forged).is_err());
+
+        assert!(bc.verify_chain(VERIFY_UTXO_SET + 1).is_err());
+        assert!(bc.verify_chain(0).unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_creation_leaves_the_store_untouched() {
+        let alice = wallet(1);
+        let store = Arc::new(MemoryStore::new());
+        let greedy = Transaction::new_coinbase(alice.get_address(), String::new(), 1).unwrap();
+        let genesis = Block::new_block(vec![greedy], String::new(), 0, TARGET_BITS).unwrap();
+
+        assert!(Blockchain::create_from_genesis_with_store(store.clone(), &genesis, true).is_err());
+        assert!(store.get_tip().unwrap().is_none());
+        assert!(!store.tx_index_enabled().unwrap());
+    }
+
+    #[test]
+    fn reorganizes_onto_a_longer_branch() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let bc = chain(&alice);
+        let genesis = bc.get_block_by_height(0).unwrap();
+        let genesis_coinbase = coinbase(&bc, 0);
+
+        let to_bob = spend(&alice, &genesis_coinbase, 0, vec![pay(&bob, 10)], SEQUENCE_FINAL);
+        let a1 = block_on(&bc, &genesis, &alice, vec![to_bob]);
+        assert_eq!(bc.process_block(&a1).unwrap().1.len(), 1);
+
+        let to_alice = spend(&alice, &genesis_coinbase, 0, vec![pay(&alice, 10)], SEQUENCE_FINAL);
+        let b1 = block_on(&bc, &genesis, &bob, vec![to_alice]);
+        let (disconnected, connected) = bc.process_block(&b1).unwrap();
+        assert!(disconnected.is_empty() && connected.is_empty());
+        assert_eq!(bc.get_block_hash(1).unwrap(), a1.hash);
+
+        let b2 = block_on(&bc, &b1, &bob, Vec::new());
+        let (disconnected, connected) = bc.process_block(&b2).unwrap();
+        assert_eq!(disconnected.iter().map(|b| &b.hash).collect::<Vec<_>>(), vec![&a1.hash]);
+        assert_eq!(connected.iter().map(|b| &b.hash).collect::<Vec<_>>(), vec![&b1.hash, &b2.hash]);
+        assert_eq!(bc.get_block_hash(1).unwrap(), b1.hash);
+
+        let utxo_set = UTXOSet { blockchain: bc.clone() };
+        assert_eq!(utxo_set.get_balance(&bob.get_address()).unwrap(), 2 * SUBSIDY);
+        assert!(bc.verify_chain(VERIFY_UTXO_SET).unwrap().is_none());
+    }
+
+    #[test]
+    fn stays_on_the_active_chain_when_a_branch_is_invalid() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let bc = chain(&alice);
+        let genesis = bc.get_block_by_height(0).unwrap();
+        let a1 = block_on(&bc, &genesis, &alice, Vec::new());
+        bc.process_block(&a1).unwrap();
+
+        let b1 = block_on(&bc, &genesis, &bob, Vec::new());
+        bc.process_block(&b1).unwrap();
+        // Spends an output that does not exist
+        let bogus = spend(&bob, &coinbase(&bc, 1), 0, vec![pay(&bob, 10)], SEQUENCE_FINAL);
+        let b2 = block_on(&bc, &b1, &bob, vec![bogus]);
+        assert!(bc.process_block(&b2).is_err());
+
+        assert_eq!(bc.get_best_height().unwrap(), 1);
+        assert_eq!(bc.get_block_hash(1).unwrap(), a1.hash);
+        assert!(bc.verify_chain(VERIFY_UTXO_SET).unwrap().is_none());
+    }
+
+    /// Mines `block` again with another timestamp
+    fn retimed(mut block: Block, timestamp: u128) -> Block {
+        block.timestamp = timestamp;
+        block.run_proof_of_work().unwrap();
+        block
+    }
+
+    #[test]
+    fn checks_time_locks_against_the_median_time_past() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let bc = chain(&alice);
+        let genesis = bc.get_block_by_height(0).unwrap();
+        let hour = 60 * 60 * 1000;
+
+        // Locked for 512 seconds after the genesis coin; the block's own
+        // timestamp is past that, but the median time past is not
+        let locked = spend(&alice, &coinbase(&bc, 0), 0, vec![pay(&bob, 10)], SEQUENCE_TYPE_FLAG | 1);
+        let early = retimed(block_on(&bc, &genesis, &alice, vec![locked.clone()]), genesis.timestamp + hour);
+        assert!(bc.accept_block(&early).unwrap_err().to_string().contains("locked until time"));
+        assert!(bc.check_transaction(&locked, &|_, _| None).is_err());
+
+        let future = retimed(block_on(&bc, &genesis, &alice, Vec::new()), genesis.timestamp + 3 * hour);
+        assert!(bc.accept_block(&future).unwrap_err().to_string().contains("future"));
+        let ahead = retimed(block_on(&bc, &genesis, &alice, Vec::new()), genesis.timestamp + hour);
+        bc.accept_block(&ahead).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use crate::block::Block;
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Portable block file layout: `MAGIC`, a little-endian `u32` version,
+/// then every block as a little-endian `u32` length followed by the
+/// bincode-serialized block, in height order
+const MAGIC: &[u8; 4] = b"BCRS";
+const VERSION: u32 = 1;
+/// Upper bound on a single record, so a corrupt length cannot make us
+/// allocate unbounded memory
+const MAX_RECORD_SIZE: u32 = 32 * 1024 * 1024;
+
+pub struct BlockFileWriter {
+    writer: BufWriter<File>,
+    count: usize,
+}
+
+impl BlockFileWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(BlockFileWriter { writer, count: 0 })
+    }
+
+    pub fn write_block(&mut self, block: &Block) -> Result<()> {
+        let data = serialize(block)?;
+        self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&data)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Flushes the file and returns the number of blocks written
+    pub fn finish(mut self) -> Result<usize> {
+        self.writer.flush()?;
+        Ok(self.count)
+    }
+}
+
+pub struct BlockFileReader {
+    reader: BufReader<File>,
+}
+
+impl BlockFileReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)
+            .map_err(|_| anyhow!("Not a block file: missing header"))?;
+        if &magic != MAGIC {
+            return Err(anyhow!("Not a block file: bad magic"));
+        }
+
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != VERSION {
+            return Err(anyhow!("Unsupported block file version {}", version));
+        }
+
+        Ok(BlockFileReader { reader })
+    }
+
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        let mut len = [0u8; 4];
+        if self.reader.read(&mut len[..1])? == 0 {
+            return Ok(None);
+        }
+        self.reader.read_exact(&mut len[1..])
+            .map_err(|_| anyhow!("Block file is truncated"))?;
+
+        let len = u32::from_le_bytes(len);
+        if len > MAX_RECORD_SIZE {
+            return Err(anyhow!("Block record of {} bytes exceeds the limit", len));
+        }
+
+        let mut data = vec![0u8; len as usize];
+        self.reader.read_exact(&mut data)
+            .map_err(|_| anyhow!("Block file is truncated"))?;
+        Ok(Some(deserialize(&data)?))
+    }
+}
+
+impl Iterator for BlockFileReader {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_block().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::{chain, wallet};
+    use crate::blockchain::Blockchain;
+    use crate::store::MemoryStore;
+    use crate::transaction::Transaction;
+    use std::sync::Arc;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("blockfile-{}-{}", std::process::id(), name))
+    }
+
+    /// Writes the blocks of a four block chain and returns them
+    fn write_chain(path: &Path) -> Vec<Block> {
+        let miner = wallet(1);
+        let mut bc = chain(&miner);
+        for _ in 0..3 {
+            bc.mine_block(vec![Transaction::new_coinbase(miner.get_address(), String::new(), 0).unwrap()]).unwrap();
+        }
+        let blocks: Vec<Block> = (0..4).map(|height| bc.get_block_by_height(height).unwrap()).collect();
+
+        let mut writer = BlockFileWriter::create(path).unwrap();
+        for block in &blocks {
+            writer.write_block(block).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), blocks.len());
+        blocks
+    }
+
+    #[test]
+    fn blocks_read_back_in_order() {
+        let path = temp_path("round-trip");
+        let blocks = write_chain(&path);
+        let read: Vec<Block> = BlockFileReader::open(&path).unwrap().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        let hashes = |blocks: &[Block]| blocks.iter().map(|block| block.hash.clone()).collect::<Vec<_>>();
+        assert_eq!(hashes(&read), hashes(&blocks));
+        assert_eq!(serialize(&read).unwrap(), serialize(&blocks).unwrap());
+    }
+
+    #[test]
+    fn truncated_files_import_up_to_the_cut() {
+        let path = temp_path("truncated");
+        let blocks = write_chain(&path);
+        let full = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &full[..full.len() - 10]).unwrap();
+
+        let mut reader = BlockFileReader::open(&path).unwrap();
+        let bc = Blockchain::create_from_genesis_with_store(Arc::new(MemoryStore::new()), &reader.next().unwrap().unwrap(), true).unwrap();
+        let mut error = None;
+        for block in reader {
+            match block {
+                Ok(block) => assert!(bc.import_block(&block).unwrap()),
+                Err(e) => error = Some(e),
+            }
+        }
+        assert_eq!(error.unwrap().to_string(), "Block file is truncated");
+        assert_eq!(bc.get_best_height().unwrap(), 2);
+
+        // Importing the whole file again skips what is there and adds the rest
+        std::fs::write(&path, &full).unwrap();
+        let imported: Vec<bool> = BlockFileReader::open(&path).unwrap()
+            .map(|block| bc.import_block(&block.unwrap()).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(imported, [false, false, false, true]);
+        assert_eq!(bc.get_best_height().unwrap(), 3);
+        assert_eq!(bc.get_block_by_height(3).unwrap().hash, blocks[3].hash);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = temp_path("other");
+        std::fs::write(&path, b"BCWL\x01\x00\x00\x00").unwrap();
+        assert!(BlockFileReader::open(&path).is_err());
+        std::fs::write(&path, [MAGIC.as_slice(), &2u32.to_le_bytes()].concat()).unwrap();
+        assert!(BlockFileReader::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,17 +1,48 @@
-use crate::blockchain::Blockchain;
-use crate::server::{start_full_node, start_miner_node};
-use crate::transaction::Transaction;
-use crate::utxoset::UTXOSet;
-use crate::wallets::Wallets;
+use crate::block::Block;
+use crate::blockchain::{Blockchain, VERIFY_UTXO_SET};
+use crate::blockfile::{BlockFileReader, BlockFileWriter};
+use crate::fees::{fee_for_size, DEFAULT_TARGET, MAX_TARGET};
+use crate::history::WalletHistory;
+use crate::mempool::{Mempool, DEFAULT_MAX_MEMPOOL_SIZE, DEFAULT_MEMPOOL_EXPIRY_HOURS};
+use crate::psbt::PartiallySignedTx;
+use crate::server::{
+    broadcast_transaction, fetch_fee_estimate, fetch_mempool_info, fetch_mempool_transaction, fetch_tx_outs, fetch_utxos, start_full_node,
+    start_miner_node, DEFAULT_NODE,
+};
+use crate::transaction::{sum_values, TXOutput, Transaction, MAX_MONEY, SEQUENCE_MASK};
+use crate::utxoset::{Coin, UTXOSet};
+use crate::script::{Htlc, MultisigPolicy, Script, Timelock};
+use crate::wallets::{list_wallets, select_wallet, selected_wallet, verify_message, write_private, Wallets};
 use anyhow::anyhow;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use clap::{Parser, Subcommand};
 use anyhow::Result;
 
+/// Data directory used unless --datadir is given
+const DEFAULT_DATA_DIR: &str = "data";
+/// Environment variables passphrases can be passed in instead of typing
+/// them, which keeps them off the command line
+const PASSPHRASE_ENV: &str = "WALLET_PASSPHRASE";
+const NEW_PASSPHRASE_ENV: &str = "WALLET_NEW_PASSPHRASE";
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Command,
+    /// Prompt for the passphrase of an encrypted wallet. It can also be
+    /// passed in the WALLET_PASSPHRASE environment variable.
+    #[arg(long, global = true, action = clap::ArgAction::SetTrue)]
+    pub passphrase: bool,
+    /// Wallet to use instead of the selected one
+    #[arg(long, global = true)]
+    pub wallet: Option<String>,
+    /// Directory holding the chain, wallets and node state
+    #[arg(long, global = true, default_value = DEFAULT_DATA_DIR)]
+    pub datadir: PathBuf,
 }
 
 #[derive(Subcommand)]
@@ -25,6 +56,9 @@ pub enum Command {
     /// Create a new blockchain
     CreateBlockchain {
         address: String,
+        /// Maintain a txid -> block index for get-tx
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        txindex: bool,
     },
     /// Print blockchain info
     Info,
@@ -32,6 +66,15 @@ pub enum Command {
     StartNode {
         port: u16,
         miner_address: Option<String>,
+        /// Most bytes of unconfirmed transactions to keep
+        #[arg(long, default_value_t = DEFAULT_MAX_MEMPOOL_SIZE)]
+        max_mempool: usize,
+        /// Hours after which unconfirmed transactions are dropped
+        #[arg(long, default_value_t = DEFAULT_MEMPOOL_EXPIRY_HOURS)]
+        mempool_expiry: u64,
+        /// Node to relay new transactions and blocks to; may be repeated
+        #[arg(long = "peer")]
+        peers: Vec<String>,
     },
     /// Send coins from one address to another
     Send {
@@ -41,8 +84,307 @@ pub enum Command {
         to: String,
         #[arg(long)]
         amount: i32,
+        /// Hex data to embed in an extra unspendable output
+        #[arg(long)]
+        data: Option<String>,
+        /// Fee paid to the miner on top of the amount. Estimated by the
+        /// node by default.
+        #[arg(long)]
+        fee: Option<i32>,
+        /// Allow replacing the transaction with bump-fee while unconfirmed
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        rbf: bool,
+        /// Mine the transaction locally instead of submitting it to a node
         #[arg(long, action = clap::ArgAction::SetTrue)] 
         mine: bool,
+        #[arg(long, default_value = DEFAULT_NODE)]
+        node: String,
+    },
+    /// Pay several recipients in one transaction
+    SendMany {
+        #[arg(long)]
+        from: String,
+        /// Payments as ADDRESS=AMOUNT
+        #[arg(required_unless_present = "file")]
+        payments: Vec<String>,
+        /// CSV file of `address,amount` lines, or a .json file holding an
+        /// object of address -> amount or a list of {"address", "amount"}
+        #[arg(long)]
+        file: Option<String>,
+        #[arg(long)]
+        fee: Option<i32>,
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        rbf: bool,
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        mine: bool,
+        #[arg(long, default_value = DEFAULT_NODE)]
+        node: String,
+    },
+    /// Replace an unconfirmed transaction sent with --rbf by one paying a higher fee out of its change
+    BumpFee {
+        txid: String,
+        /// New total fee; one more than the current fee by default
+        #[arg(long)]
+        fee: Option<i32>,
+        /// Index of the change output to take the fee from. By default the
+        /// only output paying back to the address of the inputs.
+        #[arg(long)]
+        change: Option<usize>,
+        #[arg(long, default_value = DEFAULT_NODE)]
+        node: String,
+    },
+    /// Estimate the fee rate that gets a transaction confirmed within a number of blocks
+    EstimateFee {
+        #[arg(long, default_value_t = DEFAULT_TARGET)]
+        target: usize,
+        #[arg(long, default_value = DEFAULT_NODE)]
+        node: String,
+    },
+    /// Show the size, minimum fee and fee rates of a node's mempool
+    MempoolInfo {
+        #[arg(long, default_value = DEFAULT_NODE)]
+        node: String,
+    },
+    /// Timestamp a file by embedding its SHA-256 hash in a transaction
+    Notarize {
+        file: String,
+        /// Address paying for the transaction; its coins come back as change
+        #[arg(long)]
+        from: String,
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        mine: bool,
+        #[arg(long, default_value = DEFAULT_NODE)]
+        node: String,
+    },
+    /// Find the blocks whose transactions embed a hash or other data
+    FindTimestamp {
+        /// Hex data to look for
+        #[arg(required_unless_present = "file")]
+        data: Option<String>,
+        /// Look for the SHA-256 hash of this file instead
+        #[arg(long, conflicts_with = "data")]
+        file: Option<String>,
+    },
+    /// Print the public key of an address, to share with multisig cosigners
+    GetPubkey {
+        address: String,
+    },
+    /// Add an m-of-n multisig address to the wallet
+    CreateMultisig {
+        /// Signatures needed to spend
+        #[arg(long)]
+        required: u8,
+        /// Hex public keys of the cosigners, or addresses of this wallet
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Add an address that pays to ADDRESS once a lock time has passed, for vesting and escrow
+    CreateTimelock {
+        #[arg(long)]
+        address: String,
+        /// Block height, or unix time from 500000000 on, from which the coins can be spent
+        #[arg(long, required_unless_present = "blocks")]
+        until: Option<u32>,
+        /// Blocks that must pass after each payment to the address before it can be spent
+        #[arg(long, conflicts_with = "until")]
+        blocks: Option<u32>,
+    },
+    /// Add a hash time-locked contract address that pays RECIPIENT against a secret, or refunds SENDER after the timeout
+    HtlcCreate {
+        #[arg(long)]
+        recipient: String,
+        #[arg(long)]
+        sender: String,
+        /// Hex SHA-256 hash of the secret; a new secret is generated when omitted
+        #[arg(long)]
+        hash: Option<String>,
+        /// Block height, or unix time from 500000000 on, from which the sender can take the coins back
+        #[arg(long)]
+        timeout: u32,
+    },
+    /// Spend the coins of an HTLC address with the secret
+    HtlcClaim {
+        address: String,
+        /// Hex secret whose SHA-256 hash the HTLC is locked to
+        #[arg(long)]
+        preimage: String,
+        /// Pay here instead of the recipient address
+        #[arg(long)]
+        to: Option<String>,
+        /// Fee paid to the miner out of the coins. Estimated by the node
+        /// by default.
+        #[arg(long)]
+        fee: Option<i32>,
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        mine: bool,
+        #[arg(long, default_value = DEFAULT_NODE)]
+        node: String,
+    },
+    /// Take back the coins of an HTLC address once its timeout has passed
+    HtlcRefund {
+        address: String,
+        /// Pay here instead of the sender address
+        #[arg(long)]
+        to: Option<String>,
+        /// Fee paid to the miner out of the coins. Estimated by the node
+        /// by default.
+        #[arg(long)]
+        fee: Option<i32>,
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        mine: bool,
+        #[arg(long, default_value = DEFAULT_NODE)]
+        node: String,
+    },
+    /// Build a payment without signing it, for an offline signer or multisig cosigners
+    CreateUnsignedTx {
+        #[arg(long)]
+        from: String,
+        #[arg(long)]
+        to: String,
+        #[arg(long)]
+        amount: i32,
+        /// Fee paid to the miner on top of the amount. Estimated by the
+        /// node by default, or none with --local.
+        #[arg(long)]
+        fee: Option<i32>,
+        /// File to write the unsigned transaction to
+        #[arg(long)]
+        out: String,
+        /// Block height or unix time before which the payment cannot be mined
+        #[arg(long)]
+        lock_time: Option<u32>,
+        /// Take the coins from the local chain instead of a running node
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        local: bool,
+        #[arg(long, default_value = DEFAULT_NODE)]
+        node: String,
+    },
+    /// Sign or co-sign the inputs of a transaction file that belong to this wallet. Needs no chain.
+    SignTx {
+        file: String,
+        /// Write the result here instead of updating the file in place
+        #[arg(long)]
+        out: Option<String>,
+    },
+    /// Submit a fully signed transaction file to a node's mempool
+    BroadcastTx {
+        file: String,
+        #[arg(long, default_value = DEFAULT_NODE)]
+        node: String,
+    },
+    /// Print a block by hash or by height
+    GetBlock {
+        hash: Option<String>,
+        #[arg(long, conflicts_with = "hash")]
+        height: Option<i32>,
+    },
+    /// Print a transaction by id (requires the transaction index)
+    GetTx {
+        txid: String,
+    },
+    /// Check the whole chain from genesis to tip
+    VerifyChain {
+        /// 0: PoW, linkage and timestamps, 1: merkle roots, 2: difficulty,
+        /// 3: transactions and signatures, 4: compare the UTXO set
+        #[arg(long, default_value_t = VERIFY_UTXO_SET)]
+        level: u32,
+    },
+    /// Write the chain to a portable block file
+    ExportBlocks {
+        file: String,
+    },
+    /// Validate and append blocks from a block file, creating the chain if needed
+    ImportBlocks {
+        file: String,
+        /// Enable the transaction index when the import creates the chain
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        txindex: bool,
+    },
+    /// Recreate the HD wallet from a mnemonic and rescan the chain for its addresses
+    RestoreWallet {
+        #[arg(long)]
+        mnemonic: String,
+        #[arg(long, default_value_t = 0)]
+        account: u32,
+    },
+    /// Track an address without its private key (watch-only)
+    ImportAddress {
+        address: String,
+        /// Leave rebuilding the wallet history to the next list-transactions
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        no_rescan: bool,
+    },
+    /// Print the private key of an address
+    DumpPrivkey {
+        address: String,
+    },
+    /// Add a private key printed by dump-privkey
+    ImportPrivkey {
+        key: String,
+        /// Leave rebuilding the wallet history to the next list-transactions
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        no_rescan: bool,
+    },
+    /// Write all keys, the HD seed and watch-only addresses to a text file
+    ExportWallet {
+        file: String,
+    },
+    /// Merge the keys of a file written by export-wallet
+    ImportWallet {
+        file: String,
+        /// Leave rebuilding the wallet history to the next list-transactions
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        no_rescan: bool,
+    },
+    /// Prove control of an address by signing a message with its key
+    SignMessage {
+        #[arg(long)]
+        address: String,
+        #[arg(long)]
+        message: String,
+    },
+    /// Check a signature made by sign-message
+    VerifyMessage {
+        #[arg(long)]
+        address: String,
+        #[arg(long)]
+        signature: String,
+        #[arg(long)]
+        message: String,
+    },
+    /// List the wallet's transactions, newest first
+    ListTransactions {
+        #[arg(long, default_value_t = 20)]
+        count: usize,
+    },
+    /// Rebuild the wallet's transaction history from the chain
+    Rescan,
+    /// Create an empty named wallet, encrypted if a passphrase is given
+    NewWallet {
+        name: String,
+    },
+    /// Add an existing wallet file as a named wallet
+    LoadWallet {
+        file: String,
+        #[arg(long)]
+        name: String,
+    },
+    /// Use a wallet by default for later commands
+    SelectWallet {
+        name: String,
+    },
+    /// List the wallets and mark the selected one
+    ListWallets,
+    /// Encrypt the wallet file with a passphrase
+    EncryptWallet,
+    /// Change the passphrase of an encrypted wallet. The new one can be
+    /// passed in WALLET_NEW_PASSPHRASE.
+    ChangePassphrase,
+    /// Rebuild the block indexes and the UTXO set
+    Reindex {
+        /// Also enable and build the transaction index
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        txindex: bool,
     },
 }
 
@@ -51,32 +393,128 @@ impl Cli {
         match &self.command {
             Command::CreateWallet => self.cmd_create_wallet(),
             Command::GetBalance { ref address } => self.cmd_get_balance(address),
-            Command::CreateBlockchain { ref address } => self.cmd_create_blockchain(address),
+            Command::CreateBlockchain { ref address, txindex } => self.cmd_create_blockchain(address, *txindex),
             Command::Info => self.cmd_info(),
-            Command::StartNode { port, ref miner_address } => self.cmd_start_node(*port, miner_address),
-            Command::Send { ref from, ref to, amount, mine } => self.cmd_send(from, to, *amount, *mine),
+            Command::StartNode { port, ref miner_address, max_mempool, mempool_expiry, ref peers } => {
+                self.cmd_start_node(*port, miner_address, Mempool::new(*max_mempool, *mempool_expiry), peers.clone())
+            }
+            Command::Send { ref from, ref to, amount, ref data, fee, rbf, mine, ref node } => {
+                let mut outputs = vec![TXOutput::new(*amount, to)?];
+                if let Some(data) = data {
+                    outputs.push(TXOutput::new_data(&hex::decode(data).map_err(|_| anyhow!("Data must be hex"))?)?);
+                }
+                self.cmd_send(from, outputs, *fee, *rbf, *mine, node)
+            }
+            Command::SendMany { ref from, ref payments, ref file, fee, rbf, mine, ref node } => {
+                let outputs = payment_outputs(payments, file)?;
+                let total = sum_values(outputs.iter().map(|out| out.value))?;
+                println!("Paying {} to {} recipients", total, outputs.len());
+                self.cmd_send(from, outputs, *fee, *rbf, *mine, node)
+            }
+            Command::BumpFee { ref txid, fee, change, ref node } => self.cmd_bump_fee(txid, *fee, *change, node),
+            Command::EstimateFee { target, ref node } => self.cmd_estimate_fee(*target, node),
+            Command::MempoolInfo { ref node } => self.cmd_mempool_info(node),
+            Command::Notarize { ref file, ref from, mine, ref node } => self.cmd_notarize(file, from, *mine, node),
+            Command::FindTimestamp { ref data, ref file } => self.cmd_find_timestamp(data, file),
+            Command::GetPubkey { ref address } => self.cmd_get_pubkey(address),
+            Command::CreateMultisig { required, ref keys } => self.cmd_create_multisig(*required, keys),
+            Command::CreateTimelock { ref address, until, blocks } => self.cmd_create_timelock(address, *until, *blocks),
+            Command::HtlcCreate { ref recipient, ref sender, ref hash, timeout } => self.cmd_htlc_create(recipient, sender, hash, *timeout),
+            Command::HtlcClaim { ref address, ref preimage, ref to, fee, mine, ref node } => {
+                let preimage = hex::decode(preimage).map_err(|_| anyhow!("Preimage must be hex"))?;
+                self.cmd_spend_htlc(address, Some(preimage), to, *fee, *mine, node)
+            }
+            Command::HtlcRefund { ref address, ref to, fee, mine, ref node } => self.cmd_spend_htlc(address, None, to, *fee, *mine, node),
+            Command::CreateUnsignedTx { ref from, ref to, amount, fee, ref out, lock_time, local, ref node } => {
+                let node = (!*local).then_some(node.as_str());
+                self.cmd_create_unsigned_tx(from, TXOutput::new(*amount, to)?, *fee, *lock_time, out, node)
+            }
+            Command::SignTx { ref file, ref out } => self.cmd_sign_tx(file, out),
+            Command::BroadcastTx { ref file, ref node } => self.cmd_broadcast_tx(file, node),
+            Command::GetBlock { ref hash, height } => self.cmd_get_block(hash, *height),
+            Command::GetTx { ref txid } => self.cmd_get_tx(txid),
+            Command::VerifyChain { level } => self.cmd_verify_chain(*level),
+            Command::ExportBlocks { ref file } => self.cmd_export_blocks(file),
+            Command::ImportBlocks { ref file, txindex } => self.cmd_import_blocks(file, *txindex),
+            Command::RestoreWallet { ref mnemonic, account } => self.cmd_restore_wallet(mnemonic, *account),
+            Command::ImportAddress { ref address, no_rescan } => self.cmd_import_address(address, *no_rescan),
+            Command::DumpPrivkey { ref address } => self.cmd_dump_privkey(address),
+            Command::ImportPrivkey { ref key, no_rescan } => self.cmd_import_privkey(key, *no_rescan),
+            Command::ExportWallet { ref file } => self.cmd_export_wallet(file),
+            Command::ImportWallet { ref file, no_rescan } => self.cmd_import_wallet(file, *no_rescan),
+            Command::SignMessage { ref address, ref message } => self.cmd_sign_message(address, message),
+            Command::VerifyMessage { ref address, ref signature, ref message } => self.cmd_verify_message(address, signature, message),
+            Command::ListTransactions { count } => self.cmd_list_transactions(*count),
+            Command::Rescan => self.cmd_rescan(),
+            Command::NewWallet { ref name } => self.cmd_new_wallet(name),
+            Command::LoadWallet { ref file, ref name } => self.cmd_load_wallet(file, name),
+            Command::SelectWallet { ref name } => self.cmd_select_wallet(name),
+            Command::ListWallets => self.cmd_list_wallets(),
+            Command::EncryptWallet => self.cmd_encrypt_wallet(),
+            Command::ChangePassphrase => self.cmd_change_passphrase(),
+            Command::Reindex { txindex } => self.cmd_reindex(*txindex),
         }
     }
 
+    /// Name of the wallet this command works on
+    fn wallet_name(&self) -> Result<String> {
+        match &self.wallet {
+            Some(name) => Ok(name.clone()),
+            None => selected_wallet(&self.datadir),
+        }
+    }
+
+    /// Loads the wallet file without unlocking it
+    fn load_wallets(&self) -> Result<Wallets> {
+        Wallets::open(&self.datadir, &self.wallet_name()?)
+    }
+
+    /// Loads the wallet file, unlocking it when a passphrase was given
+    fn open_wallets(&self) -> Result<Wallets> {
+        let mut wallets = self.load_wallets()?;
+        if wallets.is_encrypted() {
+            if let Some(passphrase) = self.passphrase(false)? {
+                wallets.unlock(&passphrase)?;
+            }
+        }
+        Ok(wallets)
+    }
+
+    /// Passphrase of the wallet from `PASSPHRASE_ENV`, or typed at a
+    /// prompt when --passphrase is given or one is `required`
+    fn passphrase(&self, required: bool) -> Result<Option<String>> {
+        if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+            return Ok(Some(passphrase));
+        }
+        if !self.passphrase && !required {
+            return Ok(None);
+        }
+        Ok(Some(prompt_passphrase("Wallet passphrase: ")?))
+    }
+
     fn cmd_create_wallet(&self) -> Result<()> {
-        let mut wallets = Wallets::new()?;
-        let address = wallets.create_wallet();
+        let mut wallets = self.open_wallets()?;
+        let (address, mnemonic) = wallets.create_wallet()?;
         wallets.save_all()?;
         println!("Wallet created");
+        if let Some(mnemonic) = mnemonic {
+            println!("New HD seed, write down this mnemonic to restore the wallet:");
+            println!("{}", mnemonic);
+        }
         println!("Address: {}", address);
         Ok(())
     }
 
     fn cmd_get_balance(&self, address: &str) -> Result<()> {
-        let bc = Blockchain::open()?;
+        let bc = Blockchain::open(&self.datadir)?;
         let utxo_set = UTXOSet { blockchain: bc };
         let balance = utxo_set.get_balance(address)?;
         println!("Balance of '{}': {}", address, balance);
         Ok(())
     }
 
-    fn cmd_create_blockchain(&self, address: &str) -> Result<()> {
-        let bc = Blockchain::create_blockchain(address)?;
+    fn cmd_create_blockchain(&self, address: &str, txindex: bool) -> Result<()> {
+        let bc = Blockchain::create_blockchain(&self.datadir, address, txindex)?;
         let utxo_set = UTXOSet { blockchain: bc };
         utxo_set.reindex()?;
         println!("Blockchain created");
@@ -84,13 +522,13 @@ impl Cli {
     }
 
     fn cmd_info(&self) -> Result<()> {
-        let bc = Blockchain::open()?;
+        let bc = Blockchain::open(&self.datadir)?;
         let utxo_set = UTXOSet { blockchain: bc };
         let best_height = utxo_set.blockchain.get_best_height()?;
         let block_count = utxo_set.blockchain.get_block_count()?;
         let utxo_count = utxo_set.count_transactions()?;
         
-        let wallets = Wallets::new()?;
+        let wallets = self.load_wallets()?;
         let addresses = wallets.get_all_addresses();
         
         println!("Blockchain Info:");
@@ -98,7 +536,11 @@ impl Cli {
         println!("Blocks:         {}", block_count);
         println!("Best Height:    {}", best_height);
         println!("UTXO Count:     {}", utxo_count);
+        println!("Wallet:         {}", wallets.name());
         println!("Wallet Count:   {}", addresses.len());
+        if wallets.is_encrypted() {
+            println!("Wallet Status:  encrypted, locked");
+        }
         
         if !addresses.is_empty() {
             println!();
@@ -107,46 +549,889 @@ impl Cli {
             
             for addr in &addresses {
                 let balance = utxo_set.get_balance(addr)?;
-                println!("{:<34} : {:>8} BTC", addr, balance);
+                if wallets.is_watch_only(addr) {
+                    println!("{:<34} : {:>8} BTC  (watch-only)", addr, balance);
+                } else if let Some(script) = wallets.get_script(addr) {
+                    println!("{:<34} : {:>8} BTC  ({})", addr, balance, describe_script(script));
+                } else {
+                    println!("{:<34} : {:>8} BTC", addr, balance);
+                }
             }
         }
         
         Ok(())
     }
 
-    fn cmd_start_node(&self, port: u16, miner_address: &Option<String>) -> Result<()> {
-        let bc = Blockchain::open()?;
+    fn cmd_start_node(&self, port: u16, miner_address: &Option<String>, mempool: Mempool, peers: Vec<String>) -> Result<()> {
+        let bc = Blockchain::open(&self.datadir)?;
         let utxo_set = UTXOSet { blockchain: bc };
         
         if let Some(addr) = miner_address {
             println!("Starting miner node on port {}", port);
-            start_miner_node(port, addr, utxo_set)?;
+            start_miner_node(port, addr, utxo_set, mempool, peers, &self.datadir)?;
         } else {
             println!("Starting full node on port {}", port);
-            start_full_node(port, utxo_set)?;
+            start_full_node(port, utxo_set, mempool, peers, &self.datadir)?;
         }
         
         Ok(())
     }
 
-    fn cmd_send(&self, from: &str, to: &str, amount: i32, mine: bool) -> Result<()> {
-        let bc = Blockchain::open()?;
-        let utxo_set = UTXOSet { blockchain: bc.clone() };
+    /// Sends `outputs` paid from `from`. Without a `fee`, one is estimated
+    /// by the node for `DEFAULT_TARGET` blocks; mining locally pays none.
+    fn cmd_send(&self, from: &str, outputs: Vec<TXOutput>, fee: Option<i32>, rbf: bool, mine: bool, node: &str) -> Result<()> {
+        let wallets = self.open_wallets()?;
+        if wallets.get_script(from).is_none() {
+            wallets.get_wallet(from)?;
+        }
+        let coins = self.spendable_coins(from, mine, node)?;
         
-        let wallets = Wallets::new()?;
-        let wallet = wallets.get_wallet(from)
-            .ok_or_else(|| anyhow!("Wallet not found"))?;
+        let build = |fee: i32| -> Result<Transaction> {
+            let (mut tx, prev_outputs) = Transaction::new_unsigned(from, outputs.clone(), fee, &coins)?;
+            if rbf {
+                tx.signal_rbf();
+            }
+            let mut psbt = PartiallySignedTx::new(tx, prev_outputs)?;
+            add_redeem_script(&wallets, from, &mut psbt)?;
+            psbt.sign(&wallets)?;
+            psbt.finalize()
+        };
         
-        let tx = Transaction::new_utxo(wallet, to, amount, &utxo_set)?;
+        let tx = build_with_fee(fee, mine, node, build, Transaction::size)?;
+        submit_transaction(&self.datadir, tx, mine, node)
+    }
+
+    fn cmd_notarize(&self, file: &str, from: &str, mine: bool, node: &str) -> Result<()> {
+        let hash = Sha256::digest(std::fs::read(file)?);
+        println!("SHA-256 of {}: {}", file, hex::encode(hash));
+        self.cmd_send(from, vec![TXOutput::new_data(&hash)?], None, false, mine, node)
+    }
+
+    fn cmd_mempool_info(&self, node: &str) -> Result<()> {
+        let info = fetch_mempool_info(node)?;
+        println!("Transactions   {}", info.count);
+        println!("Size           {} of {} bytes", info.size, info.max_size);
+        println!("Minimum fee    {} per 1000 bytes", info.min_fee_rate);
+        println!("Expiry         {} hours", info.expiry_hours);
+        if !info.histogram.is_empty() {
+            println!();
+            println!("{:>12} {:>8} {:>10}", "Fee rate", "Count", "Bytes");
+            for (fee_rate, count, size) in info.histogram {
+                let range = match fee_rate {
+                    0 | 1 => fee_rate.to_string(),
+                    _ => format!("{}-{}", fee_rate, fee_rate * 2 - 1),
+                };
+                println!("{:>12} {:>8} {:>10}", range, count, size);
+            }
+        }
+        Ok(())
+    }
+
+    fn cmd_estimate_fee(&self, target: usize, node: &str) -> Result<()> {
+        if !(1..=MAX_TARGET).contains(&target) {
+            return Err(anyhow!("Target must be between 1 and {} blocks", MAX_TARGET));
+        }
+        match fetch_fee_estimate(node, target as u32)? {
+            Some(fee_rate) => println!("{} per 1000 bytes for confirmation within {} blocks", fee_rate, target),
+            None => println!("Not enough blocks seen yet to estimate a fee for {} blocks", target),
+        }
+        Ok(())
+    }
+
+    fn cmd_bump_fee(&self, txid: &str, fee: Option<i32>, change: Option<usize>, node: &str) -> Result<()> {
+        let old = fetch_mempool_transaction(node, txid)?;
+        if !old.signals_rbf() {
+            return Err(anyhow!("Transaction {} does not signal replace-by-fee. Send with --rbf to allow bumping", txid));
+        }
+        
+        let outpoints = old.vin.iter().map(|input| (input.txid.clone(), input.vout)).collect();
+        let mut prev_outputs = Vec::new();
+        for (input, output) in old.vin.iter().zip(fetch_tx_outs(node, outpoints)?) {
+            let output = output
+                .ok_or_else(|| anyhow!("Input {}:{} is not in the UTXO set of {}", input.txid, input.vout, node))?;
+            prev_outputs.push(output);
+        }
+        let input_value = sum_values(prev_outputs.iter().map(|out| out.value))?;
+        let output_value = sum_values(old.vout.iter().map(|out| out.value))?;
+        let old_fee = input_value - output_value;
+        let new_fee = fee.unwrap_or(old_fee + 1);
+        if new_fee > MAX_MONEY {
+            return Err(anyhow!("Amount too large: fee is over {}", MAX_MONEY));
+        }
+        if new_fee <= old_fee {
+            return Err(anyhow!("New fee must be higher than the current fee of {}", old_fee));
+        }
         
-        if mine {
-            let mut bc_mine = bc.clone();
-            bc_mine.mine_block(vec![tx])?;
+        let wallets = self.open_wallets()?;
+        let change = match change {
+            Some(change) => {
+                let output = old.vout.get(change)
+                    .ok_or_else(|| anyhow!("Transaction {} has no output {}", txid, change))?;
+                if !output.script_pubkey.address().is_some_and(|address| wallets.get_all_addresses().contains(&address)) {
+                    return Err(anyhow!("Output {} of {} does not pay this wallet", change, txid));
+                }
+                change
+            }
+            None => find_change(&old, &prev_outputs)?,
+        };
+        
+        let mut tx = old.clone();
+        if tx.vout[change].value < new_fee - old_fee {
+            return Err(anyhow!("Change of {} cannot cover a fee increase of {}", tx.vout[change].value, new_fee - old_fee));
+        }
+        tx.vout[change].value -= new_fee - old_fee;
+        if tx.vout[change].value == 0 && tx.vout.len() == 1 {
+            return Err(anyhow!("The fee increase takes all of output {}, the only output of {}", change, txid));
+        }
+        if tx.vout[change].value == 0 {
+            tx.vout.remove(change);
+        }
+        tx.id.clear();
+        for input in &mut tx.vin {
+            input.script_sig = Script::default();
+        }
+        
+        let mut psbt = PartiallySignedTx::new(tx, prev_outputs.clone())?;
+        for output in &prev_outputs {
+            if let Some(script) = output.script_pubkey.address().and_then(|address| wallets.get_script(&address)) {
+                psbt.add_redeem_script(script)?;
+            }
+        }
+        psbt.sign(&wallets)?;
+        let tx = psbt.finalize()?;
+        broadcast_transaction(node, &tx)?;
+        println!("Replaced {} with {}, fee {} -> {}", txid, tx.id, old_fee, new_fee);
+        Ok(())
+    }
+
+    fn cmd_find_timestamp(&self, data: &Option<String>, file: &Option<String>) -> Result<()> {
+        let data = match (data, file) {
+            (_, Some(file)) => Sha256::digest(std::fs::read(file)?).to_vec(),
+            (Some(data), None) => hex::decode(data).map_err(|_| anyhow!("Data must be hex"))?,
+            (None, None) => return Err(anyhow!("Give the data or --file")),
+        };
+        
+        let bc = Blockchain::open(&self.datadir)?;
+        let mut found = Vec::new();
+        for block in bc.iter() {
+            let block = block?;
+            for tx in &block.transactions {
+                if tx.vout.iter().any(|out| out.script_pubkey.data_payload() == Some(data.as_slice())) {
+                    found.push((block.height, block.hash.clone(), block.timestamp, tx.id.clone()));
+                }
+            }
+        }
+        
+        if found.is_empty() {
+            println!("{} is not embedded in any block", hex::encode(&data));
+            return Ok(());
+        }
+        for (height, hash, timestamp, txid) in found.into_iter().rev() {
+            println!("Height {} block {} at {} ms, transaction {}", height, hash, timestamp, txid);
+        }
+        Ok(())
+    }
+
+    fn cmd_get_pubkey(&self, address: &str) -> Result<()> {
+        let wallets = self.open_wallets()?;
+        let pub_key = wallets.get_wallet(address)?.public_key();
+        println!("{}", hex::encode(pub_key));
+        Ok(())
+    }
+
+    fn cmd_create_multisig(&self, required: u8, keys: &[String]) -> Result<()> {
+        let mut wallets = self.open_wallets()?;
+        let mut pub_keys = Vec::new();
+        for key in keys {
+            let pub_key = match wallets.find_pub_key(key) {
+                Some(pub_key) => pub_key,
+                None => hex::decode(key)
+                    .map_err(|_| anyhow!("{} is neither a hex public key nor an unlocked address of this wallet", key))?,
+            };
+            pub_keys.push(pub_key);
+        }
+        
+        let policy = MultisigPolicy::new(required, pub_keys)?;
+        let n = policy.pub_keys.len();
+        let address = wallets.add_script(&policy.redeem_script())?;
+        wallets.save_all()?;
+        println!("Multisig {}-of-{} address: {}", required, n, address);
+        self.rescan_history(&wallets)
+    }
+
+    fn cmd_create_timelock(&self, address: &str, until: Option<u32>, blocks: Option<u32>) -> Result<()> {
+        let script = Script::from_address(address)?;
+        if script.p2pkh_hash().is_none() {
+            return Err(anyhow!("{} is not a single-key address", address));
+        }
+        let lock = match (until, blocks) {
+            (Some(until), _) => Timelock::Absolute(until),
+            (None, Some(blocks)) if blocks > 0 && blocks <= SEQUENCE_MASK => Timelock::Relative(blocks),
+            (None, Some(_)) => return Err(anyhow!("--blocks must be between 1 and {}", SEQUENCE_MASK)),
+            (None, None) => return Err(anyhow!("Give --until or --blocks")),
+        };
+        
+        let mut wallets = self.load_wallets()?;
+        let timelock_address = wallets.add_script(&Script::timelock(lock, &script))?;
+        wallets.save_all()?;
+        println!("Pays {} {}", address, lock);
+        println!("Timelock address: {}", timelock_address);
+        self.rescan_history(&wallets)
+    }
+
+    fn cmd_htlc_create(&self, recipient: &str, sender: &str, hash: &Option<String>, timeout: u32) -> Result<()> {
+        let (hash, secret) = match hash {
+            Some(hash) => (hex::decode(hash).map_err(|_| anyhow!("Hash must be hex"))?, None),
+            None => {
+                let mut secret = [0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                (Sha256::digest(secret).to_vec(), Some(secret))
+            }
+        };
+        let htlc = Htlc::new(hash, recipient, sender, timeout)?;
+        
+        let mut wallets = self.load_wallets()?;
+        let address = wallets.add_script(&htlc.redeem_script())?;
+        wallets.save_all()?;
+        if let Some(secret) = secret {
+            println!("Secret:         {}", hex::encode(secret));
+            println!("Keep the secret private until you claim the other side of the swap");
+        }
+        println!("Hash:           {}", hex::encode(&htlc.hash));
+        println!("HTLC address:   {}", address);
+        self.rescan_history(&wallets)
+    }
+
+    /// Spends everything at an HTLC address: a claim with `preimage`,
+    /// otherwise a refund
+    fn cmd_spend_htlc(&self, address: &str, preimage: Option<Vec<u8>>, to: &Option<String>, fee: Option<i32>, mine: bool, node: &str) -> Result<()> {
+        let wallets = self.open_wallets()?;
+        let script = wallets.get_script(address)
+            .ok_or_else(|| anyhow!("HTLC address {} is not in the wallet. Add it with htlc-create", address))?;
+        let htlc = script.parse_htlc()
+            .ok_or_else(|| anyhow!("{} is not an HTLC address", address))?;
+        let to = match (to, &preimage) {
+            (Some(to), _) => to.clone(),
+            (None, Some(_)) => htlc.recipient_address(),
+            (None, None) => htlc.sender_address(),
+        };
+        
+        let coins = self.spendable_coins(address, mine, node)?;
+        let amount = sum_values(coins.iter().map(|coin| coin.output.value))?;
+        if amount == 0 {
+            return Err(anyhow!("No coins at {}", address));
+        }
+        
+        // The fee comes out of the spent amount
+        let build = |fee: i32| -> Result<Transaction> {
+            if fee >= amount {
+                return Err(anyhow!("Fee {} leaves nothing of the {} at {}", fee, amount, address));
+            }
+            let (tx, prev_outputs) = Transaction::new_unsigned(address, vec![TXOutput::new(amount - fee, &to)?], fee, &coins)?;
+            let mut psbt = PartiallySignedTx::new(tx, prev_outputs)?;
+            psbt.add_redeem_script(script)?;
+            match &preimage {
+                Some(preimage) if psbt.add_preimage(preimage) == 0 => {
+                    return Err(anyhow!("Preimage does not match the HTLC hash"));
+                }
+                Some(_) => {}
+                None => psbt.tx.set_lock_time(htlc.timeout),
+            }
+            psbt.sign(&wallets)?;
+            psbt.finalize()
+        };
+        let tx = build_with_fee(fee, mine, node, build, Transaction::size)?;
+        submit_transaction(&self.datadir, tx, mine, node)
+    }
+
+    /// Writes an unsigned payment to `out`, taking the coins from `node`,
+    /// or from the local chain when it is `None`
+    fn cmd_create_unsigned_tx(&self, from: &str, payment: TXOutput, fee: Option<i32>, lock_time: Option<u32>, out: &str, node: Option<&str>) -> Result<()> {
+        let (local, node) = (node.is_none(), node.unwrap_or(DEFAULT_NODE));
+        let coins = self.spendable_coins(from, local, node)?;
+        let wallets = self.load_wallets()?;
+        
+        let build = |fee: i32| -> Result<PartiallySignedTx> {
+            let (mut tx, prev_outputs) = Transaction::new_unsigned(from, vec![payment.clone()], fee, &coins)?;
+            if let Some(lock_time) = lock_time {
+                tx.set_lock_time(lock_time);
+            }
+            let mut psbt = PartiallySignedTx::new(tx, prev_outputs)?;
+            add_redeem_script(&wallets, from, &mut psbt)?;
+            Ok(psbt)
+        };
+        let psbt = build_with_fee(fee, local, node, build, PartiallySignedTx::signed_size)?;
+        psbt.save(out)?;
+        println!("Unsigned transaction with {} inputs written to {}", psbt.tx.vin.len(), out);
+        Ok(())
+    }
+
+    fn cmd_sign_tx(&self, file: &str, out: &Option<String>) -> Result<()> {
+        let mut psbt = PartiallySignedTx::load(file)?;
+        let wallets = self.open_wallets()?;
+        
+        println!("Transaction to sign:");
+        for output in &psbt.tx.vout {
+            println!("Pay            {:>8} -> {}", output.value, output.address());
+        }
+        println!("Fee            {:>8}", psbt.fee()?);
+        if psbt.tx.lock_time != 0 {
+            println!("Lock time      {:>8}", psbt.tx.lock_time);
+        }
+        
+        let signed = psbt.sign(&wallets)?;
+        psbt.save(out.as_deref().unwrap_or(file))?;
+        println!("Added {} signatures, {} of {} inputs fully signed", signed, psbt.signed_inputs(), psbt.tx.vin.len());
+        if let Ok(tx) = psbt.finalize() {
+            println!("Transaction {} is complete and ready for broadcast-tx", tx.id);
+        }
+        Ok(())
+    }
+
+    fn cmd_broadcast_tx(&self, file: &str, node: &str) -> Result<()> {
+        let tx = PartiallySignedTx::load(file)?.finalize()?;
+        let txid = broadcast_transaction(node, &tx)?;
+        println!("Transaction {} accepted by {}", txid, node);
+        Ok(())
+    }
+
+    fn cmd_get_block(&self, hash: &Option<String>, height: Option<i32>) -> Result<()> {
+        let bc = Blockchain::open(&self.datadir)?;
+        let block = match (hash, height) {
+            (Some(hash), _) => bc.get_block(hash)?,
+            (None, Some(height)) => bc.get_block_by_height(height)?,
+            (None, None) => return Err(anyhow!("Either a block hash or --height is required")),
+        };
+        
+        print_block(&block);
+        Ok(())
+    }
+
+    fn cmd_get_tx(&self, txid: &str) -> Result<()> {
+        let bc = Blockchain::open(&self.datadir)?;
+        let (tx, location) = bc.get_transaction(txid)?;
+        let block = bc.get_block(&location.block_hash)?;
+        
+        println!("Transaction {}", tx.id);
+        println!("{}", "=".repeat(40));
+        println!("Block:          {}", location.block_hash);
+        println!("Height:         {}", block.height);
+        println!("Position:       {}", location.position);
+        print_transaction(&tx);
+        Ok(())
+    }
+
+    fn cmd_verify_chain(&self, level: u32) -> Result<()> {
+        let bc = Blockchain::open(&self.datadir)?;
+        match bc.verify_chain(level)? {
+            None => {
+                println!("Chain verified: {} blocks at level {}", bc.get_block_count()?, level);
+                Ok(())
+            }
+            Some(failure) => {
+                println!("Verification failed at height {}", failure.height);
+                println!("Block:          {}", failure.hash);
+                println!("Reason:         {}", failure.reason);
+                Err(anyhow!("Chain verification failed"))
+            }
+        }
+    }
+
+    fn cmd_export_blocks(&self, file: &str) -> Result<()> {
+        let bc = Blockchain::open(&self.datadir)?;
+        let mut writer = BlockFileWriter::create(file)?;
+        for height in 0..=bc.get_best_height()? {
+            writer.write_block(&bc.get_block_by_height(height)?)?;
+        }
+        
+        let count = writer.finish()?;
+        println!("Exported {} blocks to {}", count, file);
+        Ok(())
+    }
+
+    fn cmd_import_blocks(&self, file: &str, txindex: bool) -> Result<()> {
+        let mut reader = BlockFileReader::open(file)?;
+        let mut imported = 0;
+        let mut skipped = 0;
+        
+        let bc = if Blockchain::exists(&self.datadir)? {
+            Blockchain::open(&self.datadir)?
+        } else {
+            let genesis = reader.next()
+                .ok_or_else(|| anyhow!("Block file is empty"))??;
+            imported += 1;
+            Blockchain::create_from_genesis(&self.datadir, &genesis, txindex)?
+        };
+        
+        for block in reader {
+            let block = block?;
+            let connected = bc.import_block(&block)
+                .map_err(|e| anyhow!("Block {} at height {} rejected: {}", block.hash, block.height, e))?;
+            if connected {
+                imported += 1;
+            } else {
+                skipped += 1;
+            }
+        }
+        
+        println!("Imported {} blocks, skipped {} already known", imported, skipped);
+        println!("Best Height:    {}", bc.get_best_height()?);
+        Ok(())
+    }
+
+    fn cmd_restore_wallet(&self, mnemonic: &str, account: u32) -> Result<()> {
+        let bc = if Blockchain::exists(&self.datadir)? {
+            Some(Blockchain::open(&self.datadir)?)
         } else {
-            // TODO: 发送交易到网络
+            None
+        };
+        
+        let mut used = HashSet::new();
+        if let Some(bc) = &bc {
+            for block in bc.iter() {
+                for tx in block?.transactions {
+                    for output in tx.vout {
+                        used.insert(output.address());
+                    }
+                }
+            }
         }
         
-        println!("Success!");
+        let mut wallets = self.open_wallets()?;
+        let addresses = wallets.restore(mnemonic, account, |address| used.contains(address))?;
+        wallets.save_all()?;
+        
+        println!("Restored {} addresses", addresses.len());
+        println!("{}", "-".repeat(30));
+        let utxo_set = bc.map(|blockchain| UTXOSet { blockchain });
+        for address in &addresses {
+            let balance = match &utxo_set {
+                Some(utxo_set) => utxo_set.get_balance(address)?,
+                None => 0,
+            };
+            let path = wallets.hd_path(address).unwrap_or_default();
+            println!("{:<34} : {:>8} BTC  {}", address, balance, path);
+        }
+        Ok(())
+    }
+
+    fn cmd_import_address(&self, address: &str, no_rescan: bool) -> Result<()> {
+        let mut wallets = self.load_wallets()?;
+        if !wallets.import_address(address)? {
+            println!("Address {} is already watched", address);
+            return Ok(());
+        }
+        wallets.save_all()?;
+        println!("Watching {}", address);
+        
+        if !no_rescan {
+            self.rescan_history(&wallets)?;
+        }
+        Ok(())
+    }
+
+    fn cmd_dump_privkey(&self, address: &str) -> Result<()> {
+        let wallets = self.open_wallets()?;
+        println!("{}", wallets.dump_privkey(address)?);
+        Ok(())
+    }
+
+    fn cmd_import_privkey(&self, key: &str, no_rescan: bool) -> Result<()> {
+        let mut wallets = self.open_wallets()?;
+        let (address, added) = wallets.import_privkey(key)?;
+        if !added {
+            println!("Key for {} is already in the wallet", address);
+            return Ok(());
+        }
+        wallets.save_all()?;
+        println!("Imported key for {}", address);
+        
+        if !no_rescan {
+            self.rescan_history(&wallets)?;
+        }
+        Ok(())
+    }
+
+    fn cmd_export_wallet(&self, file: &str) -> Result<()> {
+        let wallets = self.open_wallets()?;
+        write_private(file, wallets.export_dump()?.as_bytes())?;
+        println!("Exported {} addresses to {}", wallets.get_all_addresses().len(), file);
+        Ok(())
+    }
+
+    fn cmd_import_wallet(&self, file: &str, no_rescan: bool) -> Result<()> {
+        let text = std::fs::read_to_string(file)?;
+        let mut wallets = self.open_wallets()?;
+        let added = wallets.import_dump(&text)?;
+        wallets.save_all()?;
+        println!("Imported {} new addresses from {}", added, file);
+        
+        if added > 0 && !no_rescan {
+            self.rescan_history(&wallets)?;
+        }
+        Ok(())
+    }
+
+    fn cmd_sign_message(&self, address: &str, message: &str) -> Result<()> {
+        let wallets = self.open_wallets()?;
+        let wallet = wallets.get_wallet(address)?;
+        println!("{}", wallet.sign_message(message));
+        Ok(())
+    }
+
+    fn cmd_verify_message(&self, address: &str, signature: &str, message: &str) -> Result<()> {
+        if !verify_message(address, signature, message)? {
+            return Err(anyhow!("Signature is not valid for this address and message"));
+        }
+        println!("Signature verified");
+        Ok(())
+    }
+
+    fn cmd_list_transactions(&self, count: usize) -> Result<()> {
+        let bc = Blockchain::open(&self.datadir)?;
+        let wallets = self.load_wallets()?;
+        let mut history = WalletHistory::load(&self.datadir, wallets.name())?;
+        history.sync(&bc, &wallets.get_all_addresses())?;
+        history.save()?;
+        
+        let best_height = bc.get_best_height()?;
+        println!("{:<8} {:>6} {:<12} {:>8} {:>6}  Txid", "Height", "Confs", "Category", "Amount", "Fee");
+        println!("{}", "-".repeat(100));
+        for tx in history.transactions().iter().rev().take(count) {
+            let fee = tx.fee.map(|fee| fee.to_string()).unwrap_or_else(|| "-".to_string());
+            println!(
+                "{:<8} {:>6} {:<12} {:>8} {:>6}  {}",
+                tx.height,
+                best_height - tx.height + 1,
+                format!("{:?}", tx.category),
+                tx.amount(),
+                fee,
+                tx.txid
+            );
+        }
+        Ok(())
+    }
+
+    fn cmd_rescan(&self) -> Result<()> {
+        let bc = Blockchain::open(&self.datadir)?;
+        let wallets = self.load_wallets()?;
+        let mut history = WalletHistory::load(&self.datadir, wallets.name())?;
+        history.rescan(&bc, &wallets.get_all_addresses())?;
+        history.save()?;
+        println!("Rescanned {} blocks, found {} wallet transactions", bc.get_block_count()?, history.transactions().len());
+        Ok(())
+    }
+
+    fn cmd_new_wallet(&self, name: &str) -> Result<()> {
+        let mut wallets = Wallets::create(&self.datadir, name)?;
+        if self.passphrase || std::env::var_os(PASSPHRASE_ENV).is_some() {
+            wallets.encrypt(&new_passphrase(PASSPHRASE_ENV)?)?;
+            wallets.save_all()?;
+        }
+        println!("Wallet '{}' created", name);
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn cmd_load_wallet(&self, file: &str, name: &str) -> Result<()> {
+        let wallets = Wallets::load_file(&self.datadir, file, name)?;
+        println!("Wallet '{}' loaded with {} addresses", name, wallets.get_all_addresses().len());
+        self.rescan_history(&wallets)
+    }
+
+    fn cmd_select_wallet(&self, name: &str) -> Result<()> {
+        select_wallet(&self.datadir, name)?;
+        println!("Selected wallet '{}'", name);
+        Ok(())
+    }
+
+    fn cmd_list_wallets(&self) -> Result<()> {
+        let selected = selected_wallet(&self.datadir)?;
+        for name in list_wallets(&self.datadir)? {
+            let wallets = Wallets::open(&self.datadir, &name)?;
+            let marker = if name == selected { "*" } else { " " };
+            let line = format!("{} {:<20} {:>4} addresses", marker, name, wallets.get_all_addresses().len());
+            if wallets.is_encrypted() {
+                println!("{}  encrypted", line);
+            } else {
+                println!("{}", line);
+            }
+        }
+        Ok(())
+    }
+
+    fn cmd_encrypt_wallet(&self) -> Result<()> {
+        let mut wallets = self.load_wallets()?;
+        wallets.encrypt(&new_passphrase(PASSPHRASE_ENV)?)?;
+        wallets.save_all()?;
+        println!("Wallet encrypted. Signing commands now need --passphrase");
+        Ok(())
+    }
+
+    fn cmd_change_passphrase(&self) -> Result<()> {
+        let mut wallets = self.load_wallets()?;
+        let passphrase = self.passphrase(true)?.unwrap_or_default();
+        wallets.change_passphrase(&passphrase, &new_passphrase(NEW_PASSPHRASE_ENV)?)?;
+        wallets.save_all()?;
+        println!("Wallet passphrase changed");
+        Ok(())
+    }
+
+    fn cmd_reindex(&self, txindex: bool) -> Result<()> {
+        let mut bc = Blockchain::open(&self.datadir)?;
+        if txindex && !bc.has_tx_index()? {
+            bc.enable_tx_index()?;
+        }
+        bc.rebuild_indexes()?;
+        
+        let utxo_set = UTXOSet { blockchain: bc };
+        let count = utxo_set.reindex()?;
+        println!("Reindexed {} blocks, {} transactions with unspent outputs", utxo_set.blockchain.get_block_count()?, count);
+        Ok(())
+    }
+
+    /// Coins of `address` to spend: from the local chain when `local`,
+    /// otherwise from the node, which keeps the chain locked while it
+    /// runs and leaves out coins its mempool already spends
+    fn spendable_coins(&self, address: &str, local: bool, node: &str) -> Result<Vec<Coin>> {
+        let script_pubkey = Script::from_address(address)?;
+        if local {
+            UTXOSet { blockchain: Blockchain::open(&self.datadir)? }.find_coins(&script_pubkey)
+        } else {
+            fetch_utxos(node, &script_pubkey)
+        }
+    }
+
+    /// Rebuilds the wallet history after new addresses were added
+    fn rescan_history(&self, wallets: &Wallets) -> Result<()> {
+        if !Blockchain::exists(&self.datadir)? {
+            return Ok(());
+        }
+        let bc = Blockchain::open(&self.datadir)?;
+        let mut history = WalletHistory::load(&self.datadir, wallets.name())?;
+        history.rescan(&bc, &wallets.get_all_addresses())?;
+        history.save()?;
+        println!("Rescanned {} blocks, found {} wallet transactions", bc.get_block_count()?, history.transactions().len());
+        Ok(())
+    }
+}
+
+/// Passphrase to encrypt a wallet with, from the environment variable
+/// `env` or typed twice at a prompt
+fn new_passphrase(env: &str) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(env) {
+        return Ok(passphrase);
+    }
+    let passphrase = prompt_passphrase("New wallet passphrase: ")?;
+    if prompt_passphrase("Repeat the passphrase: ")? != passphrase {
+        return Err(anyhow!("Passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+/// Reads a passphrase from the terminal without echoing it
+fn prompt_passphrase(prompt: &str) -> Result<String> {
+    rpassword::prompt_password(prompt)
+        .map_err(|e| anyhow!("Cannot read a passphrase from the terminal ({}). Set {} instead", e, PASSPHRASE_ENV))
+}
+
+fn print_block(block: &Block) {
+    println!("Block {}", block.hash);
+    println!("{}", "=".repeat(40));
+    println!("Height:         {}", block.height);
+    println!("Prev Hash:      {}", block.prev_block_hash);
+    println!("Timestamp:      {}", block.timestamp);
+    println!("Nonce:          {}", block.nonce);
+    println!("Difficulty:     {}", block.difficulty);
+    println!("Transactions:   {}", block.transactions.len());
+    
+    for tx in &block.transactions {
+        println!();
+        println!("Transaction {}", tx.id);
+        print_transaction(tx);
+    }
+}
+
+fn print_transaction(tx: &Transaction) {
+    println!("{}", "-".repeat(30));
+    if tx.lock_time != 0 {
+        println!("Lock Time:      {}", tx.lock_time);
+    }
+    if tx.is_coinbase() {
+        println!("Input:          coinbase");
+    } else {
+        for input in &tx.vin {
+            if tx.version >= 2 && input.relative_lock().is_some() {
+                println!("Input:          {}:{} (locked {})", input.txid, input.vout, Timelock::Relative(input.sequence));
+            } else {
+                println!("Input:          {}:{}", input.txid, input.vout);
+            }
+            if let Some(preimage) = Htlc::revealed_preimage(&input.script_sig) {
+                println!("Preimage:       {}", hex::encode(preimage));
+            }
+        }
+    }
+    for (idx, output) in tx.vout.iter().enumerate() {
+        match output.script_pubkey.data_payload() {
+            Some(data) => println!("Output {:<7} {:>8} data {}", format!("{}:", idx), output.value, hex::encode(data)),
+            None => println!("Output {:<7} {:>8} -> {}", format!("{}:", idx), output.value, output.address()),
+        }
+    }
+}
+
+fn parse_amount(amount: &str) -> Result<i32> {
+    amount.trim().parse().map_err(|_| anyhow!("Invalid amount '{}'", amount.trim()))
+}
+
+/// Outputs for ADDRESS=AMOUNT `payments` and the pairs in `file`
+fn payment_outputs(payments: &[String], file: &Option<String>) -> Result<Vec<TXOutput>> {
+    let mut pairs = Vec::new();
+    for payment in payments {
+        let (address, amount) = payment.split_once('=')
+            .ok_or_else(|| anyhow!("Expected ADDRESS=AMOUNT, got {}", payment))?;
+        pairs.push((address.to_string(), parse_amount(amount)?));
+    }
+    if let Some(file) = file {
+        pairs.extend(read_payments(file)?);
+    }
+    
+    let mut outputs = Vec::new();
+    for (address, amount) in &pairs {
+        if *amount <= 0 {
+            return Err(anyhow!("Amount for {} must be positive", address));
+        }
+        outputs.push(TXOutput::new(*amount, address)?);
+    }
+    Ok(outputs)
+}
+
+/// Reads address/amount pairs from a JSON file (by its `.json`
+/// extension) or a CSV file, where a header line is skipped
+fn read_payments(file: &str) -> Result<Vec<(String, i32)>> {
+    let text = std::fs::read_to_string(file)?;
+    let bad_file = || anyhow!("{} must hold an object of address -> amount or a list of {{\"address\", \"amount\"}}", file);
+    
+    if file.ends_with(".json") {
+        let value: serde_json::Value = serde_json::from_str(&text)?;
+        let pair = |address: Option<&str>, amount: Option<i64>| -> Result<(String, i32)> {
+            match (address, amount.and_then(|amount| i32::try_from(amount).ok())) {
+                (Some(address), Some(amount)) => Ok((address.to_string(), amount)),
+                _ => Err(bad_file()),
+            }
+        };
+        return match value {
+            serde_json::Value::Object(map) => map.iter()
+                .map(|(address, amount)| pair(Some(address), amount.as_i64()))
+                .collect(),
+            serde_json::Value::Array(items) => items.iter()
+                .map(|item| pair(item["address"].as_str(), item["amount"].as_i64()))
+                .collect(),
+            _ => Err(bad_file()),
+        };
+    }
+    
+    let mut pairs = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (address, amount) = line.split_once(',')
+            .ok_or_else(|| anyhow!("{} line {}: expected address,amount", file, number + 1))?;
+        match parse_amount(amount) {
+            Ok(amount) => pairs.push((address.trim().to_string(), amount)),
+            Err(_) if pairs.is_empty() && number == 0 => continue,
+            Err(e) => return Err(anyhow!("{} line {}: {}", file, number + 1, e)),
+        }
+    }
+    Ok(pairs)
+}
+
+/// Builds with `fee`, or else with the node's fee estimate unless the
+/// coins come from the local chain, rebuilding until the fee covers the
+/// size `size` reports
+fn build_with_fee<T>(fee: Option<i32>, local: bool, node: &str, build: impl Fn(i32) -> Result<T>, size: impl Fn(&T) -> Result<usize>) -> Result<T> {
+    let fee_rate = match fee {
+        Some(_) => None,
+        None if local => None,
+        None => {
+            let fee_rate = fetch_fee_estimate(node, DEFAULT_TARGET as u32)?;
+            if fee_rate.is_none() {
+                println!("The node has no fee estimate yet, using no fee");
+            }
+            fee_rate
+        }
+    };
+    let mut built = build(fee.unwrap_or(0))?;
+    if let Some(fee_rate) = fee_rate {
+        // The fee can change the size, so build until it covers it
+        let mut fee = 0;
+        while fee_for_size(fee_rate, size(&built)?) > fee {
+            fee = fee_for_size(fee_rate, size(&built)?);
+            built = build(fee)?;
+        }
+        println!("Estimated fee {} at {} per 1000 bytes", fee, fee_rate);
+    }
+    Ok(built)
+}
+
+/// Index of the change output of `tx`: the only output paying back to
+/// the address all of its inputs spend from. A lone output is the
+/// payment, since change always follows one.
+fn find_change(tx: &Transaction, prev_outputs: &[TXOutput]) -> Result<usize> {
+    let script_pubkey = &prev_outputs[0].script_pubkey;
+    if prev_outputs.iter().any(|out| out.script_pubkey != *script_pubkey) {
+        return Err(anyhow!("Transaction {} spends from several addresses. Pick the change output with --change", tx.id));
+    }
+    if tx.vout.len() < 2 {
+        return Err(anyhow!("Transaction {} has no change output to take the fee from", tx.id));
+    }
+    let mut change = tx.vout.iter().enumerate()
+        .filter(|(_, out)| out.script_pubkey == *script_pubkey)
+        .map(|(idx, _)| idx);
+    match (change.next(), change.next()) {
+        (Some(idx), None) => Ok(idx),
+        (None, _) => Err(anyhow!("Transaction {} has no change output to take the fee from", tx.id)),
+        (Some(_), Some(_)) => Err(anyhow!("Transaction {} pays its own address more than once. Pick the change output with --change", tx.id)),
+    }
+}
+
+/// Mines `tx` locally or submits it to the node at `node`
+fn submit_transaction(data_dir: &Path, tx: Transaction, mine: bool, node: &str) -> Result<()> {
+    if mine {
+        Blockchain::open(data_dir)?.mine_block(vec![tx])?;
+    } else {
+        broadcast_transaction(node, &tx)?;
+        println!("Transaction {} sent to {}", tx.id, node);
+    }
+    
+    println!("Success!");
+    Ok(())
+}
+
+/// Attaches the redeem script of a P2SH `from` address to the inputs
+/// spending it
+fn add_redeem_script(wallets: &Wallets, from: &str, psbt: &mut PartiallySignedTx) -> Result<()> {
+    if let Some(script) = wallets.get_script(from) {
+        psbt.add_redeem_script(script)?;
+    } else if Script::from_address(from)?.p2pkh_hash().is_none() {
+        return Err(anyhow!("Script address {} is not in the wallet. Add it with create-multisig, create-timelock or htlc-create", from));
+    }
+    Ok(())
+}
+
+/// Short description of a redeem script for listings
+fn describe_script(script: &Script) -> String {
+    if let Some((required, keys)) = script.parse_multisig() {
+        return format!("multisig {}-of-{}", required, keys.len());
+    }
+    if let Some(htlc) = script.parse_htlc() {
+        return format!("htlc to {}, refundable at lock time {}", htlc.recipient_address(), htlc.timeout);
+    }
+    match script.parse_timelock() {
+        Some((lock, inner)) => format!("{} {}", describe_script(&inner), lock),
+        None => match script.address() {
+            Some(address) => format!("pays {}", address),
+            None => "script".to_string(),
+        },
+    }
+}
@@ -0,0 +1,187 @@
+/// Largest confirmation target, in blocks, that fees are estimated for
+pub const MAX_TARGET: usize = 25;
+/// Target used when a payment is sent without an explicit fee
+pub const DEFAULT_TARGET: usize = 6;
+/// Share of transactions in a fee rate range that must have confirmed
+/// within the target for the range to be recommended
+const SUCCESS_THRESHOLD: f64 = 0.85;
+/// Fewest transactions, after decay, an estimate is based on
+const MIN_SAMPLES: f64 = 3.0;
+/// Weight kept by older observations each block, so estimates follow
+/// recent conditions
+const DECAY: f64 = 0.99;
+/// Bucket 0 holds fee rate 0 and bucket `i` rates from `2^(i-1)` up to
+/// `2^i`
+const BUCKETS: usize = 32;
+
+/// Learns from the blocks a node mines how long transactions at each fee
+/// rate wait for confirmation, and recommends fee rates for a target
+/// number of blocks
+pub struct FeeEstimator {
+    /// Per bucket, how many transactions confirmed within `i + 1` blocks
+    confirmed: Vec<[f64; MAX_TARGET]>,
+    /// Per bucket, how many transactions confirmed or waited longer than
+    /// `MAX_TARGET` blocks
+    total: Vec<f64>,
+}
+
+impl Default for FeeEstimator {
+    fn default() -> Self {
+        FeeEstimator {
+            confirmed: vec![[0.0; MAX_TARGET]; BUCKETS],
+            total: vec![0.0; BUCKETS],
+        }
+    }
+}
+
+impl FeeEstimator {
+    /// Ages the recorded history by a block
+    pub fn new_block(&mut self) {
+        for (confirmed, total) in self.confirmed.iter_mut().zip(&mut self.total) {
+            confirmed.iter_mut().for_each(|count| *count *= DECAY);
+            *total *= DECAY;
+        }
+    }
+
+    /// Records a transaction paying `fee_rate` that confirmed `blocks`
+    /// blocks after it was received
+    pub fn record_confirmed(&mut self, fee_rate: i64, blocks: usize) {
+        let bucket = bucket(fee_rate);
+        for count in self.confirmed[bucket].iter_mut().skip(blocks.max(1) - 1) {
+            *count += 1.0;
+        }
+        self.total[bucket] += 1.0;
+    }
+
+    /// Records a transaction paying `fee_rate` still unconfirmed after
+    /// `MAX_TARGET` blocks
+    pub fn record_failed(&mut self, fee_rate: i64) {
+        self.total[bucket(fee_rate)] += 1.0;
+    }
+
+    /// Lowest fee rate at which transactions have reliably confirmed
+    /// within `target` blocks, or `None` without enough history.
+    /// `waiting` holds the fee rate and blocks waited so far of each
+    /// unconfirmed transaction, those that already missed the target
+    /// counting against it.
+    pub fn estimate(&self, target: usize, waiting: impl Iterator<Item = (i64, usize)>) -> Option<i64> {
+        let target = target.clamp(1, MAX_TARGET);
+        let mut missed = vec![0.0; BUCKETS];
+        for (fee_rate, blocks) in waiting {
+            if blocks >= target {
+                missed[bucket(fee_rate)] += 1.0;
+            }
+        }
+
+        // Group buckets from the highest rate down until each group has
+        // enough transactions, and keep going while groups pass
+        let mut estimate = None;
+        let (mut successes, mut attempts) = (0.0, 0.0);
+        for bucket in (0..BUCKETS).rev() {
+            successes += self.confirmed[bucket][target - 1];
+            attempts += self.total[bucket] + missed[bucket];
+            if attempts < MIN_SAMPLES {
+                continue;
+            }
+            if successes / attempts < SUCCESS_THRESHOLD {
+                break;
+            }
+            estimate = Some(lower_bound(bucket));
+            successes = 0.0;
+            attempts = 0.0;
+        }
+        estimate
+    }
+}
+
+/// Fee for a transaction of `size` bytes paying at least `fee_rate` per
+/// 1000 bytes
+pub fn fee_for_size(fee_rate: i64, size: usize) -> i32 {
+    let fee = fee_rate.saturating_mul(size as i64).saturating_add(999) / 1000;
+    fee.clamp(0, i32::MAX as i64) as i32
+}
+
+/// Lowest fee rate of the range `fee_rate` is grouped in: 0, or the
+/// power of two at or below it
+pub fn bucket_floor(fee_rate: i64) -> i64 {
+    lower_bound(bucket(fee_rate))
+}
+
+fn bucket(fee_rate: i64) -> usize {
+    if fee_rate <= 0 {
+        0
+    } else {
+        (64 - fee_rate.leading_zeros() as usize).min(BUCKETS - 1)
+    }
+}
+
+fn lower_bound(bucket: usize) -> i64 {
+    if bucket == 0 {
+        0
+    } else {
+        1 << (bucket - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Estimator that saw `count` transactions at each fee rate confirm
+    /// after the given number of blocks
+    fn estimator(history: &[(i64, usize)], count: usize) -> FeeEstimator {
+        let mut estimator = FeeEstimator::default();
+        for &(fee_rate, blocks) in history {
+            for _ in 0..count {
+                estimator.record_confirmed(fee_rate, blocks);
+            }
+        }
+        estimator
+    }
+
+    #[test]
+    fn fee_rates_group_by_powers_of_two() {
+        let floors: Vec<i64> = [-5, 0, 1, 2, 3, 4, 1000, i64::MAX].into_iter().map(bucket_floor).collect();
+        assert_eq!(floors, [0, 0, 1, 2, 2, 4, 512, 1 << (BUCKETS - 2)]);
+        assert_eq!(fee_for_size(1000, 250), 250);
+        assert_eq!(fee_for_size(1, 250), 1);
+        assert_eq!(fee_for_size(0, 250), 0);
+        assert_eq!(fee_for_size(i64::MAX / 1000, 2000), i32::MAX);
+    }
+
+    #[test]
+    fn estimates_the_lowest_rate_that_confirms_in_time() {
+        let estimator = estimator(&[(1000, 1), (10, 10)], 10);
+        assert_eq!(estimator.estimate(1, std::iter::empty()), Some(512));
+        assert_eq!(estimator.estimate(9, std::iter::empty()), Some(512));
+        assert_eq!(estimator.estimate(10, std::iter::empty()), Some(8));
+        assert_eq!(estimator.estimate(100, std::iter::empty()), Some(8));
+    }
+
+    #[test]
+    fn waiting_and_failed_transactions_count_against_their_rate() {
+        let mut estimator = estimator(&[(1000, 1), (10, 3)], 10);
+        assert_eq!(estimator.estimate(5, std::iter::empty()), Some(8));
+        assert_eq!(estimator.estimate(5, std::iter::repeat_n((10, 6), 10)), Some(512));
+        assert_eq!(estimator.estimate(5, std::iter::repeat_n((10, 4), 10)), Some(8));
+
+        for _ in 0..10 {
+            estimator.record_failed(10);
+        }
+        assert_eq!(estimator.estimate(5, std::iter::empty()), Some(512));
+    }
+
+    #[test]
+    fn falls_back_to_none_without_enough_history() {
+        assert_eq!(FeeEstimator::default().estimate(DEFAULT_TARGET, std::iter::empty()), None);
+        assert_eq!(estimator(&[(1000, 1)], 2).estimate(1, std::iter::empty()), None);
+        assert_eq!(estimator(&[(1000, 10)], 10).estimate(1, std::iter::empty()), None);
+
+        let mut estimator = estimator(&[(1000, 1)], 10);
+        assert_eq!(estimator.estimate(1, std::iter::empty()), Some(512));
+        for _ in 0..200 {
+            estimator.new_block();
+        }
+        assert_eq!(estimator.estimate(1, std::iter::empty()), None);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::script::Script;
+use crate::wallets::{wallet_path, DEFAULT_WALLET};
+use anyhow::Result;
+use bincode::{deserialize, serialize};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+const HISTORY_FILE: &str = "wallet_history";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TxCategory {
+    /// Coinbase paying one of our addresses
+    Generate,
+    Receive,
+    Send,
+    /// Spends our coins back to ourselves only
+    SelfTransfer,
+}
+
+/// A confirmed transaction touching the wallet's addresses
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalletTx {
+    pub txid: String,
+    pub block_hash: String,
+    pub height: i32,
+    pub timestamp: u128,
+    pub category: TxCategory,
+    /// Paid to our addresses by someone else
+    pub received: i32,
+    /// Paid from our coins to other addresses
+    pub sent: i32,
+    /// Paid from our coins back to our addresses
+    pub change: i32,
+    /// Known only when every input spends one of our coins
+    pub fee: Option<i32>,
+}
+
+impl WalletTx {
+    /// Net effect on the wallet balance
+    pub fn amount(&self) -> i32 {
+        match self.category {
+            TxCategory::Generate | TxCategory::Receive => self.received,
+            TxCategory::Send | TxCategory::SelfTransfer => -self.sent - self.fee.unwrap_or(0),
+        }
+    }
+}
+
+/// Transactions relevant to a wallet, built by scanning the chain.
+/// This is a cache: `rescan` can always rebuild it from the blocks.
+#[derive(Serialize, Deserialize, Default)]
+pub struct WalletHistory {
+    /// Last block scanned, used to resume and to detect reorganizations
+    tip_hash: String,
+    tip_height: i32,
+    transactions: Vec<WalletTx>,
+    /// Our outputs seen so far, to value the inputs that spend them
+    coins: BTreeMap<(String, i32), i32>,
+    /// Addresses the history covers; blocks before the tip are never
+    /// scanned for any other
+    addresses: BTreeSet<String>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl WalletHistory {
+    /// Loads the history of the wallet called `wallet` in `data_dir`
+    pub fn load(data_dir: &Path, wallet: &str) -> Result<Self> {
+        let path = if wallet == DEFAULT_WALLET {
+            data_dir.join(HISTORY_FILE)
+        } else {
+            wallet_path(data_dir, wallet)?.with_extension("history")
+        };
+        
+        // A file from an older format is rebuilt by the next sync
+        let mut history = if path.exists() {
+            deserialize(&std::fs::read(&path)?).unwrap_or_default()
+        } else {
+            WalletHistory::default()
+        };
+        history.path = path;
+        Ok(history)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.path, serialize(self)?)?;
+        Ok(())
+    }
+
+    pub fn transactions(&self) -> &[WalletTx] {
+        &self.transactions
+    }
+
+    /// Scans the blocks added since the last sync. Starts over from
+    /// genesis if the last scanned block is no longer on the active chain,
+    /// or if an address is new and its older transactions were never seen.
+    pub fn sync(&mut self, bc: &Blockchain, addresses: &[String]) -> Result<()> {
+        let on_chain = !self.tip_hash.is_empty()
+            && bc.get_block_hash(self.tip_height).ok().as_deref() == Some(self.tip_hash.as_str());
+        let new_address = addresses.iter().any(|address| !self.addresses.contains(address));
+        if !on_chain || new_address {
+            return self.rescan(bc, addresses);
+        }
+        self.scan_from(bc, addresses, self.tip_height + 1)
+    }
+
+    /// Forgets everything and rebuilds the history from genesis
+    pub fn rescan(&mut self, bc: &Blockchain, addresses: &[String]) -> Result<()> {
+        *self = WalletHistory {
+            path: std::mem::take(&mut self.path),
+            ..WalletHistory::default()
+        };
+        self.scan_from(bc, addresses, 0)?;
+        info!("Rescanned wallet history: {} transactions", self.transactions.len());
+        Ok(())
+    }
+
+    fn scan_from(&mut self, bc: &Blockchain, addresses: &[String], start: i32) -> Result<()> {
+        let mut ours = HashMap::new();
+        for address in addresses {
+            ours.insert(Script::from_address(address)?, address.clone());
+        }
+        self.addresses.extend(addresses.iter().cloned());
+
+        for height in start..=bc.get_best_height()? {
+            let block = bc.get_block_by_height(height)?;
+            self.scan_block(&block, &ours);
+            self.tip_hash = block.hash;
+            self.tip_height = height;
+        }
+        Ok(())
+    }
+
+    fn scan_block(&mut self, block: &Block, ours: &HashMap<Script, String>) {
+        for tx in &block.transactions {
+            let mut our_inputs = 0;
+            let mut all_inputs_ours = !tx.is_coinbase();
+            if !tx.is_coinbase() {
+                for input in &tx.vin {
+                    match self.coins.remove(&(input.txid.clone(), input.vout)) {
+                        Some(value) => our_inputs += value,
+                        None => all_inputs_ours = false,
+                    }
+                }
+            }
+
+            let mut our_outputs = 0;
+            let mut other_outputs = 0;
+            for (idx, output) in tx.vout.iter().enumerate() {
+                if ours.contains_key(&output.script_pubkey) {
+                    our_outputs += output.value;
+                    self.coins.insert((tx.id.clone(), idx as i32), output.value);
+                } else {
+                    other_outputs += output.value;
+                }
+            }
+
+            if our_inputs == 0 && our_outputs == 0 {
+                continue;
+            }
+
+            let category = if tx.is_coinbase() {
+                TxCategory::Generate
+            } else if our_inputs == 0 {
+                TxCategory::Receive
+            } else if other_outputs == 0 {
+                TxCategory::SelfTransfer
+            } else {
+                TxCategory::Send
+            };
+            let spending = our_inputs > 0;
+
+            self.transactions.push(WalletTx {
+                txid: tx.id.clone(),
+                block_hash: block.hash.clone(),
+                height: block.height,
+                timestamp: block.timestamp,
+                category,
+                received: if spending { 0 } else { our_outputs },
+                sent: if spending { other_outputs } else { 0 },
+                change: if spending { our_outputs } else { 0 },
+                fee: if all_inputs_ours {
+                    Some(our_inputs - our_outputs - other_outputs)
+                } else {
+                    None
+                },
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::{chain, coinbase, pay, spend, wallet};
+    use crate::transaction::{Transaction, SEQUENCE_FINAL};
+
+    #[test]
+    fn sync_rescans_for_new_addresses() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let mut bc = chain(&alice);
+        let tx = spend(&alice, &coinbase(&bc, 0), 0, vec![pay(&bob, 4), pay(&alice, 6)], SEQUENCE_FINAL);
+        let reward = Transaction::new_coinbase(alice.get_address(), String::new(), 0).unwrap();
+        bc.mine_block(vec![reward, tx]).unwrap();
+
+        let mut history = WalletHistory::default();
+        history.sync(&bc, &[alice.get_address()]).unwrap();
+        assert_eq!(history.transactions().len(), 3);
+
+        // Bob's payment is in a block scanned before his address was added
+        history.sync(&bc, &[alice.get_address(), bob.get_address()]).unwrap();
+        let categories: Vec<_> = history.transactions().iter().map(|tx| tx.category).collect();
+        assert_eq!(categories, [TxCategory::Generate, TxCategory::Generate, TxCategory::SelfTransfer]);
+    }
+}
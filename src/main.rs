@@ -4,8 +4,16 @@ use simple_logger::SimpleLogger;
 
 mod block;
 mod blockchain;
+mod blockfile;
 mod cli;
+mod fees;
+mod history;
+mod mempool;
+mod orphans;
+mod psbt;
+mod script;
 mod server;
+mod store;
 mod transaction;
 mod utxoset;
 mod wallets;
@@ -0,0 +1,803 @@
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::fees::{bucket_floor, FeeEstimator, MAX_TARGET};
+use crate::transaction::{sum_values, TXOutput, Transaction};
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File in the data directory a node keeps its unconfirmed transactions
+/// in across restarts
+pub const MEMPOOL_FILE: &str = "mempool.dat";
+const MEMPOOL_FILE_VERSION: u32 = 1;
+/// Default bound on the bytes of transactions in the mempool
+pub const DEFAULT_MAX_MEMPOOL_SIZE: usize = 5_000_000;
+/// Default age in hours after which unconfirmed transactions are dropped
+pub const DEFAULT_MEMPOOL_EXPIRY_HOURS: u64 = 14 * 24;
+/// Fee rate added on top of that of the transactions evicted from a full
+/// mempool to get the minimum fee rate for new ones
+const INCREMENTAL_FEE_RATE: i64 = 1;
+/// Seconds it takes the minimum fee rate raised by evictions to halve
+const MIN_FEE_HALF_LIFE: u64 = 12 * 60 * 60;
+
+/// Upper bound on the transactions a single replacement may evict
+pub const MAX_REPLACEMENT_EVICTIONS: usize = 100;
+/// Longest chain of unconfirmed transactions, counted from a new
+/// transaction back through its mempool ancestors, itself included
+pub const MAX_ANCESTORS: usize = 25;
+/// Most transactions a mempool transaction and its descendants may
+/// number together
+pub const MAX_DESCENDANTS: usize = 25;
+
+/// A validated transaction waiting to be mined
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: i32,
+    /// Serialized size in bytes
+    pub size: usize,
+    /// Chain height when the transaction was accepted
+    pub height: i32,
+    /// Unix time in seconds when the transaction was first accepted
+    pub time: u64,
+    /// Mempool transactions whose outputs this one spends
+    parents: HashSet<String>,
+    /// Mempool transactions spending outputs of this one
+    children: HashSet<String>,
+    /// This transaction and its mempool ancestors, which a miner has to
+    /// include along with it
+    with_ancestors: Package,
+    /// This transaction and its mempool descendants, which are evicted
+    /// along with it
+    with_descendants: Package,
+}
+
+impl MempoolEntry {
+    /// Fee per 1000 bytes
+    pub fn fee_rate(&self) -> i64 {
+        fee_rate(self.fee, self.size)
+    }
+}
+
+/// Totals over a set of related mempool transactions
+#[derive(Debug, Clone, Copy)]
+struct Package {
+    count: usize,
+    size: usize,
+    fee: i64,
+}
+
+impl Package {
+    fn new(fee: i32, size: usize) -> Self {
+        Package { count: 1, size, fee: fee as i64 }
+    }
+
+    fn include(&mut self, fee: i32, size: usize) {
+        self.count += 1;
+        self.size += size;
+        self.fee += fee as i64;
+    }
+
+    fn exclude(&mut self, fee: i32, size: usize) {
+        self.count -= 1;
+        self.size -= size;
+        self.fee -= fee as i64;
+    }
+
+    fn fee_rate(&self) -> PackageRate {
+        PackageRate { fee: self.fee, size: self.size as i64 }
+    }
+}
+
+/// Fee over size of a package, ordered without rounding
+#[derive(Debug, Clone, Copy)]
+struct PackageRate {
+    fee: i64,
+    size: i64,
+}
+
+impl Ord for PackageRate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.fee * other.size).cmp(&(other.fee * self.size))
+    }
+}
+
+impl PartialOrd for PackageRate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for PackageRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PackageRate {}
+
+/// Fee per 1000 bytes of a transaction of `size` bytes paying `fee`
+pub fn fee_rate(fee: i32, size: usize) -> i64 {
+    fee as i64 * 1000 / size.max(1) as i64
+}
+
+/// Counts of mempool transactions by fee rate, reported by `mempool-info`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MempoolInfo {
+    pub count: usize,
+    pub size: usize,
+    pub max_size: usize,
+    /// Lowest fee rate, per 1000 bytes, new transactions must pay
+    pub min_fee_rate: i64,
+    pub expiry_hours: u64,
+    /// Lower fee rate bound of each bucket with the number and bytes of
+    /// transactions in it
+    pub histogram: Vec<(i64, usize, usize)>,
+}
+
+/// Unconfirmed transactions that spend outputs of the active chain or of
+/// each other, bounded in total size and age
+pub struct Mempool {
+    entries: HashMap<String, MempoolEntry>,
+    /// Outpoints spent by mempool transactions, to reject double spends
+    spent: HashMap<(String, i32), String>,
+    /// Confirmation times of transactions that went through the mempool
+    fees: FeeEstimator,
+    /// Bytes of all transactions in the mempool
+    size: usize,
+    max_size: usize,
+    /// Seconds a transaction may wait for confirmation
+    expiry: u64,
+    /// Minimum fee rate set by the last eviction for space and the unix
+    /// time it was set; it decays from there
+    evicted_fee_rate: (i64, u64),
+}
+
+impl Mempool {
+    /// A mempool of at most `max_size` bytes whose transactions expire
+    /// after `expiry_hours`
+    pub fn new(max_size: usize, expiry_hours: u64) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            spent: HashMap::new(),
+            fees: FeeEstimator::default(),
+            size: 0,
+            max_size,
+            expiry: expiry_hours * 60 * 60,
+            evicted_fee_rate: (0, 0),
+        }
+    }
+
+    /// Validates `tx` against the chain and the other mempool transactions
+    /// and adds it. A transaction spending the same outputs as mempool
+    /// transactions that signal replace-by-fee replaces them, and their
+    /// descendants, if it pays a higher fee and fee rate. When the mempool
+    /// is full, the transactions paying the lowest fee rate are evicted,
+    /// possibly `tx` itself. Returns its fee.
+    pub fn add(&mut self, bc: &Blockchain, tx: Transaction) -> Result<i32> {
+        self.add_at(bc, tx, now()?)
+    }
+
+    /// Adds `tx` as first accepted at unix time `time`
+    fn add_at(&mut self, bc: &Blockchain, tx: Transaction, time: u64) -> Result<i32> {
+        let now = now()?;
+        self.expire(now);
+        if self.entries.contains_key(&tx.id) {
+            return Err(anyhow!("Transaction {} is already in the mempool", tx.id));
+        }
+        if now.saturating_sub(time) > self.expiry {
+            return Err(anyhow!("Transaction {} has expired", tx.id));
+        }
+        let mut conflicts = HashSet::new();
+        for input in &tx.vin {
+            if let Some(other) = self.spent.get(&(input.txid.clone(), input.vout)) {
+                if !self.entries[other].tx.signals_rbf() {
+                    return Err(anyhow!(
+                        "Output {}:{} is already spent by mempool transaction {}, which is not replaceable",
+                        input.txid, input.vout, other
+                    ));
+                }
+                conflicts.insert(other.clone());
+            }
+        }
+
+        let fee = bc.check_transaction(&tx, &|txid, vout| self.output(txid, vout))?;
+        let height = bc.get_best_height()?;
+        let size = tx.size()?;
+        let min_fee_rate = self.min_fee_rate(now);
+        // Compare fee / size without rounding
+        if (fee as i64) * 1000 < min_fee_rate * size as i64 {
+            return Err(anyhow!(
+                "Transaction {} pays fee rate {}, below the mempool minimum of {}",
+                tx.id, fee_rate(fee, size), min_fee_rate
+            ));
+        }
+        let parents: HashSet<String> = tx.vin.iter()
+            .filter(|input| self.entries.contains_key(&input.txid))
+            .map(|input| input.txid.clone())
+            .collect();
+        let mut ancestors = parents.clone();
+        for parent in &parents {
+            ancestors.extend(self.ancestors(parent));
+        }
+
+        let mut evicted = conflicts.clone();
+        for conflict in &conflicts {
+            evicted.extend(self.descendants(conflict));
+        }
+        if !conflicts.is_empty() {
+            if parents.iter().any(|parent| evicted.contains(parent)) {
+                return Err(anyhow!("Replacement {} spends outputs of a transaction it replaces", tx.id));
+            }
+            self.check_replacement(&tx.id, fee, size, &conflicts, &evicted)?;
+        }
+        self.check_chain_limits(&tx.id, &ancestors)?;
+
+        for id in &evicted {
+            info!("Transaction {} replaces mempool transaction {}", tx.id, id);
+            self.remove(id);
+        }
+        for input in &tx.vin {
+            self.spent.insert((input.txid.clone(), input.vout), tx.id.clone());
+        }
+        for parent in &parents {
+            if let Some(entry) = self.entries.get_mut(parent) {
+                entry.children.insert(tx.id.clone());
+            }
+        }
+        // A replacement never evicts its own ancestors, checked above
+        let mut with_ancestors = Package::new(fee, size);
+        for ancestor in &ancestors {
+            if let Some(entry) = self.entries.get_mut(ancestor) {
+                entry.with_descendants.include(fee, size);
+                with_ancestors.include(entry.fee, entry.size);
+            }
+        }
+        let txid = tx.id.clone();
+        self.size += size;
+        self.entries.insert(txid.clone(), MempoolEntry {
+            tx,
+            fee,
+            size,
+            height,
+            time,
+            parents,
+            children: HashSet::new(),
+            with_ancestors,
+            with_descendants: Package::new(fee, size),
+        });
+        self.trim(now);
+        if !self.entries.contains_key(&txid) {
+            return Err(anyhow!(
+                "Mempool is full; transaction {} pays fee rate {}, too low to enter",
+                txid, fee_rate(fee, size)
+            ));
+        }
+        info!("Accepted transaction {} into the mempool, fee {}", txid, fee);
+        Ok(fee)
+    }
+
+    /// Evicts transactions with their descendants while the mempool is
+    /// over its size, the lowest paying first, counting the descendants'
+    /// fees as a miner would. New transactions then have to pay more than
+    /// the evicted ones.
+    fn trim(&mut self, now: u64) {
+        if self.size <= self.max_size {
+            return;
+        }
+        // Evictions lower the totals of the remaining ancestors, which are
+        // then pushed again; outdated items are skipped
+        let mut queue: BinaryHeap<Reverse<(PackageRate, String)>> = self.entries.iter()
+            .map(|(txid, entry)| Reverse((entry.with_descendants.fee_rate(), txid.clone())))
+            .collect();
+        while self.size > self.max_size {
+            let Some(Reverse((rate, txid))) = queue.pop() else {
+                break;
+            };
+            if self.entries.get(&txid).map(|entry| entry.with_descendants.fee_rate()) != Some(rate) {
+                continue;
+            }
+            let fee_rate = rate.fee * 1000 / rate.size.max(1) + INCREMENTAL_FEE_RATE;
+            if fee_rate > self.min_fee_rate(now) {
+                self.evicted_fee_rate = (fee_rate, now);
+            }
+
+            let mut evicted = self.descendants(&txid);
+            evicted.insert(txid.clone());
+            let affected: HashSet<String> = evicted.iter()
+                .flat_map(|id| self.ancestors(id))
+                .filter(|id| !evicted.contains(id))
+                .collect();
+            info!("Mempool full, evicting transaction {} and its descendants", txid);
+            self.remove_with_descendants(&txid);
+            for id in affected {
+                queue.push(Reverse((self.entries[&id].with_descendants.fee_rate(), id)));
+            }
+        }
+    }
+
+    /// Drops transactions, with their descendants, that have waited longer
+    /// than the expiry
+    fn expire(&mut self, now: u64) {
+        let expired: Vec<String> = self.entries.values()
+            .filter(|entry| now.saturating_sub(entry.time) > self.expiry)
+            .map(|entry| entry.tx.id.clone())
+            .collect();
+        for txid in expired {
+            if self.entries.contains_key(&txid) {
+                info!("Transaction {} expired from the mempool", txid);
+                self.remove_with_descendants(&txid);
+            }
+        }
+    }
+
+    /// Fee rate, per 1000 bytes, a new transaction has to pay: what the
+    /// last eviction set, halving every `MIN_FEE_HALF_LIFE`, and 0 once it
+    /// drops below `INCREMENTAL_FEE_RATE`
+    fn min_fee_rate(&self, now: u64) -> i64 {
+        let (fee_rate, time) = self.evicted_fee_rate;
+        let halvings = now.saturating_sub(time) as f64 / MIN_FEE_HALF_LIFE as f64;
+        let fee_rate = (fee_rate as f64 * 0.5f64.powf(halvings)) as i64;
+        if fee_rate < INCREMENTAL_FEE_RATE {
+            0
+        } else {
+            fee_rate
+        }
+    }
+
+    /// Size, limits and fee rate histogram of the mempool
+    pub fn info(&mut self) -> Result<MempoolInfo> {
+        let now = now()?;
+        self.expire(now);
+        let mut histogram: BTreeMap<i64, (usize, usize)> = BTreeMap::new();
+        for entry in self.entries.values() {
+            let bucket = histogram.entry(bucket_floor(entry.fee_rate())).or_default();
+            bucket.0 += 1;
+            bucket.1 += entry.size;
+        }
+        Ok(MempoolInfo {
+            count: self.entries.len(),
+            size: self.size,
+            max_size: self.max_size,
+            min_fee_rate: self.min_fee_rate(now),
+            expiry_hours: self.expiry / 3600,
+            histogram: histogram.into_iter().map(|(fee_rate, (count, size))| (fee_rate, count, size)).collect(),
+        })
+    }
+
+    /// Checks that a transaction paying `fee` in `size` bytes may evict
+    /// `evicted`: it must pay more than all of them together and at a
+    /// higher rate than each of the `conflicts` it spends against
+    fn check_replacement(&self, txid: &str, fee: i32, size: usize, conflicts: &HashSet<String>, evicted: &HashSet<String>) -> Result<()> {
+        if evicted.len() > MAX_REPLACEMENT_EVICTIONS {
+            return Err(anyhow!(
+                "Replacement {} would evict {} transactions, more than {}",
+                txid, evicted.len(), MAX_REPLACEMENT_EVICTIONS
+            ));
+        }
+        let evicted_fees = sum_values(evicted.iter().map(|id| self.entries[id].fee))?;
+        if fee <= evicted_fees {
+            return Err(anyhow!(
+                "Replacement {} pays fee {}, not more than the {} of the transactions it replaces",
+                txid, fee, evicted_fees
+            ));
+        }
+        for entry in conflicts.iter().map(|id| &self.entries[id]) {
+            // Compare fee / size without rounding
+            if fee as i64 * entry.size as i64 <= entry.fee as i64 * size as i64 {
+                return Err(anyhow!(
+                    "Replacement {} pays fee rate {}, not more than the {} of {}",
+                    txid, fee_rate(fee, size), entry.fee_rate(), entry.tx.id
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects a transaction with mempool `ancestors` that would make a
+    /// chain of unconfirmed transactions longer than the limits
+    fn check_chain_limits(&self, txid: &str, ancestors: &HashSet<String>) -> Result<()> {
+        if ancestors.len() + 1 > MAX_ANCESTORS {
+            return Err(anyhow!(
+                "Transaction {} has {} unconfirmed ancestors, the limit is {}",
+                txid, ancestors.len(), MAX_ANCESTORS - 1
+            ));
+        }
+        for ancestor in ancestors {
+            if self.entries[ancestor].with_descendants.count + 1 > MAX_DESCENDANTS {
+                return Err(anyhow!(
+                    "Mempool transaction {} already has the most unconfirmed descendants allowed ({})",
+                    ancestor, MAX_DESCENDANTS - 1
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the transactions saved to `path` by `save`, validating them
+    /// again. Those that expired, were mined or conflict with the chain by
+    /// now are dropped. Does nothing if there is no file.
+    pub fn load(&mut self, bc: &Blockchain, path: &Path) -> Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+        let file: MempoolFile = deserialize(&std::fs::read(path)?)?;
+        if file.version != MEMPOOL_FILE_VERSION {
+            return Err(anyhow!("Unsupported mempool file version {}", file.version));
+        }
+
+        let mut dropped = 0;
+        for (tx, time) in file.transactions {
+            let txid = tx.id.clone();
+            if let Err(e) = self.add_at(bc, tx, time) {
+                info!("Dropping saved mempool transaction {}: {}", txid, e);
+                dropped += 1;
+            }
+        }
+        info!("Loaded {} mempool transactions from {}, dropped {}", self.entries.len(), path.display(), dropped);
+        Ok(())
+    }
+
+    /// Writes the transactions to `path`, parents before their children
+    /// so that `load` can add them in order
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = MempoolFile {
+            version: MEMPOOL_FILE_VERSION,
+            transactions: self.transactions(),
+        };
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serialize(&file)?)?;
+        Ok(())
+    }
+
+    /// Every transaction with the unix time it was first accepted,
+    /// parents before their children
+    fn transactions(&self) -> Vec<(Transaction, u64)> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| (entry.with_ancestors.count, &entry.tx.id));
+        entries.into_iter().map(|entry| (entry.tx.clone(), entry.time)).collect()
+    }
+
+    /// Output `txid:vout` of a mempool transaction
+    fn output(&self, txid: &str, vout: i32) -> Option<TXOutput> {
+        let output = self.entries.get(txid)?.tx.vout.get(usize::try_from(vout).ok()?)?;
+        (!output.script_pubkey.is_unspendable()).then(|| output.clone())
+    }
+
+    /// Every mempool transaction `txid` depends on, directly or not
+    fn ancestors(&self, txid: &str) -> HashSet<String> {
+        self.walk(txid, |entry| &entry.parents)
+    }
+
+    /// Every mempool transaction depending on `txid`, directly or not
+    fn descendants(&self, txid: &str) -> HashSet<String> {
+        self.walk(txid, |entry| &entry.children)
+    }
+
+    fn walk(&self, txid: &str, next: impl Fn(&MempoolEntry) -> &HashSet<String>) -> HashSet<String> {
+        let mut found = HashSet::new();
+        let mut queue = vec![txid.to_string()];
+        while let Some(id) = queue.pop() {
+            if let Some(entry) = self.entries.get(&id) {
+                for other in next(entry) {
+                    if found.insert(other.clone()) {
+                        queue.push(other.clone());
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    /// Whether a mempool transaction spends output `vout` of `txid`
+    pub fn spends(&self, txid: &str, vout: i32) -> bool {
+        self.spent.contains_key(&(txid.to_string(), vout))
+    }
+
+    /// Picks transactions for a block of at most `max_size` bytes of
+    /// transactions. Each transaction is weighed together with its
+    /// unselected ancestors, so a high-fee child pulls in a low-fee parent,
+    /// and the package with the highest fee rate goes first. Parents come
+    /// before their children.
+    pub fn block_template(&self, max_size: usize) -> Vec<&MempoolEntry> {
+        // Each transaction with its ancestors that are not selected yet.
+        // Selecting a package lowers the totals of its descendants, which
+        // are then pushed again; outdated items are skipped.
+        let mut packages: HashMap<&str, Package> = self.entries.iter()
+            .map(|(txid, entry)| (txid.as_str(), entry.with_ancestors))
+            .collect();
+        let mut queue: BinaryHeap<(PackageRate, Reverse<&str>)> = packages.iter()
+            .map(|(txid, package)| (package.fee_rate(), Reverse(*txid)))
+            .collect();
+        let mut selected: HashSet<&str> = HashSet::new();
+        let mut template = Vec::new();
+        let mut size = 0;
+
+        while let Some((rate, Reverse(txid))) = queue.pop() {
+            if selected.contains(txid) || packages[txid].fee_rate() != rate {
+                continue;
+            }
+            if size + packages[txid].size > max_size {
+                continue;
+            }
+            let mut package: Vec<&str> = self.ancestors(txid).iter()
+                .filter_map(|id| self.entries.get_key_value(id).map(|(id, _)| id.as_str()))
+                .filter(|id| !selected.contains(id))
+                .collect();
+            package.push(txid);
+            // A transaction has more ancestors than any of its parents
+            package.sort_by_key(|id| (self.entries[*id].with_ancestors.count, *id));
+            size += packages[txid].size;
+
+            for id in package {
+                let entry = &self.entries[id];
+                selected.insert(id);
+                template.push(entry);
+                for descendant in self.descendants(id) {
+                    let Some((descendant, _)) = self.entries.get_key_value(&descendant) else {
+                        continue;
+                    };
+                    let descendant = descendant.as_str();
+                    if !selected.contains(descendant) {
+                        let package = packages.get_mut(descendant).unwrap();
+                        package.exclude(entry.fee, entry.size);
+                        queue.push((package.fee_rate(), Reverse(descendant)));
+                    }
+                }
+            }
+        }
+        template
+    }
+
+    /// Removes `txid`, leaving its descendants in place
+    fn remove(&mut self, txid: &str) -> Option<MempoolEntry> {
+        let (fee, size) = self.entries.get(txid).map(|entry| (entry.fee, entry.size))?;
+        for ancestor in self.ancestors(txid) {
+            if let Some(entry) = self.entries.get_mut(&ancestor) {
+                entry.with_descendants.exclude(fee, size);
+            }
+        }
+        for descendant in self.descendants(txid) {
+            if let Some(entry) = self.entries.get_mut(&descendant) {
+                entry.with_ancestors.exclude(fee, size);
+            }
+        }
+        let entry = self.entries.remove(txid)?;
+        self.size -= entry.size;
+        for input in &entry.tx.vin {
+            self.spent.remove(&(input.txid.clone(), input.vout));
+        }
+        for parent in &entry.parents {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.remove(txid);
+            }
+        }
+        for child in &entry.children {
+            if let Some(child) = self.entries.get_mut(child) {
+                child.parents.remove(txid);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Removes `txid` and everything that spends its outputs
+    fn remove_with_descendants(&mut self, txid: &str) {
+        for id in self.descendants(txid) {
+            self.remove(&id);
+        }
+        self.remove(txid);
+    }
+
+    /// Lowest fee rate, per 1000 bytes, expected to get a transaction
+    /// confirmed within `target` blocks, if enough blocks have been seen
+    pub fn estimate_fee(&self, bc: &Blockchain, target: usize) -> Result<Option<i64>> {
+        let height = bc.get_best_height()?;
+        let waiting = self.entries.values()
+            .map(|entry| (entry.fee_rate(), (height - entry.height) as usize));
+        Ok(self.fees.estimate(target, waiting))
+    }
+
+    /// Drops the transactions mined in `block` and those that conflict
+    /// with it, along with the descendants of the conflicts, and records
+    /// how long the mined ones waited
+    pub fn remove_for_block(&mut self, block: &Block) {
+        self.fees.new_block();
+        for tx in &block.transactions {
+            if let Some(entry) = self.remove(&tx.id) {
+                let blocks = (block.height - entry.height) as usize;
+                // Those that waited longer were counted when they missed
+                if blocks <= MAX_TARGET {
+                    self.fees.record_confirmed(entry.fee_rate(), blocks);
+                }
+            }
+            for input in &tx.vin {
+                if let Some(conflict) = self.spent.get(&(input.txid.clone(), input.vout)).cloned() {
+                    info!("Dropping mempool transaction {} which conflicts with block {}", conflict, block.hash);
+                    self.remove_with_descendants(&conflict);
+                }
+            }
+        }
+        for entry in self.entries.values() {
+            if (block.height - entry.height) as usize == MAX_TARGET {
+                self.fees.record_failed(entry.fee_rate());
+            }
+        }
+    }
+    /// Returns the transactions of blocks a reorganization took off the
+    /// chain, given tip first, to the mempool ahead of those already
+    /// waiting, and drops the ones that do not fit the new chain
+    pub fn readd_disconnected(&mut self, bc: &Blockchain, disconnected: &[Block]) -> Result<()> {
+        let now = now()?;
+        let waiting = self.transactions();
+        self.entries.clear();
+        self.spent.clear();
+        self.size = 0;
+
+        let restored = disconnected.iter().rev()
+            .flat_map(|block| block.transactions.iter().filter(|tx| !tx.is_coinbase()))
+            .map(|tx| (tx.clone(), now));
+        for (tx, time) in restored.chain(waiting) {
+            let txid = tx.id.clone();
+            if let Err(e) = self.add_at(bc, tx, time) {
+                info!("Dropping transaction {} after reorganization: {}", txid, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// On-disk form of the mempool
+#[derive(Serialize, Deserialize)]
+struct MempoolFile {
+    version: u32,
+    /// Transactions with the unix time they were first accepted
+    transactions: Vec<(Transaction, u64)>,
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::{chain, coinbase, pay, spend, wallet};
+    use crate::transaction::{SEQUENCE_FINAL, SEQUENCE_RBF};
+
+    fn mempool() -> Mempool {
+        Mempool::new(DEFAULT_MAX_MEMPOOL_SIZE, DEFAULT_MEMPOOL_EXPIRY_HOURS)
+    }
+
+    #[test]
+    fn replaces_only_transactions_that_signal_rbf() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let mut bc = chain(&alice);
+        bc.mine_block(vec![Transaction::new_coinbase(alice.get_address(), String::new(), 0).unwrap()]).unwrap();
+        let (coin, rbf_coin) = (coinbase(&bc, 0), coinbase(&bc, 1));
+        let mut pool = mempool();
+
+        pool.add(&bc, spend(&alice, &coin, 0, vec![pay(&bob, 9)], SEQUENCE_FINAL)).unwrap();
+        assert!(pool.add(&bc, spend(&alice, &coin, 0, vec![pay(&bob, 5)], SEQUENCE_FINAL)).is_err());
+
+        let original = spend(&alice, &rbf_coin, 0, vec![pay(&bob, 9)], SEQUENCE_RBF);
+        let replacement = spend(&alice, &rbf_coin, 0, vec![pay(&bob, 7)], SEQUENCE_RBF);
+        let cheaper = spend(&alice, &rbf_coin, 0, vec![pay(&bob, 8)], SEQUENCE_RBF);
+        pool.add(&bc, original.clone()).unwrap();
+        assert_eq!(pool.add(&bc, replacement.clone()).unwrap(), 3);
+        assert!(pool.get(&original.id).is_none());
+        assert!(pool.add(&bc, cheaper).is_err());
+        assert!(pool.get(&replacement.id).is_some());
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn block_template_lets_children_pay_for_parents() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let mut bc = chain(&alice);
+        bc.mine_block(vec![Transaction::new_coinbase(alice.get_address(), String::new(), 0).unwrap()]).unwrap();
+        let mut pool = mempool();
+
+        let parent = spend(&alice, &coinbase(&bc, 0), 0, vec![pay(&bob, 10)], SEQUENCE_FINAL);
+        let child = spend(&bob, &parent, 0, vec![pay(&bob, 5)], SEQUENCE_FINAL);
+        let other = spend(&alice, &coinbase(&bc, 1), 0, vec![pay(&bob, 8)], SEQUENCE_FINAL);
+        for tx in [&parent, &child, &other] {
+            pool.add(&bc, tx.clone()).unwrap();
+        }
+
+        let ids = |template: Vec<&MempoolEntry>| template.iter().map(|entry| entry.tx.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(pool.block_template(usize::MAX)), vec![parent.id.clone(), child.id.clone(), other.id.clone()]);
+        let size = other.size().unwrap();
+        assert_eq!(ids(pool.block_template(size)), vec![other.id.clone()]);
+    }
+
+    #[test]
+    fn evicts_the_lowest_fee_rate_when_full() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let mut bc = chain(&alice);
+        bc.mine_block(vec![Transaction::new_coinbase(alice.get_address(), String::new(), 0).unwrap()]).unwrap();
+        bc.mine_block(vec![Transaction::new_coinbase(alice.get_address(), String::new(), 0).unwrap()]).unwrap();
+
+        let cheap = spend(&alice, &coinbase(&bc, 0), 0, vec![pay(&bob, 9)], SEQUENCE_FINAL);
+        let generous = spend(&alice, &coinbase(&bc, 1), 0, vec![pay(&bob, 5)], SEQUENCE_FINAL);
+        let late = spend(&alice, &coinbase(&bc, 2), 0, vec![pay(&bob, 9)], SEQUENCE_FINAL);
+        let mut pool = Mempool::new(cheap.size().unwrap() * 3 / 2, DEFAULT_MEMPOOL_EXPIRY_HOURS);
+
+        pool.add(&bc, cheap.clone()).unwrap();
+        pool.add(&bc, generous.clone()).unwrap();
+        assert!(pool.get(&cheap.id).is_none());
+        assert!(pool.get(&generous.id).is_some());
+        // The eviction raised the minimum fee rate above what it paid
+        assert!(pool.add(&bc, late).is_err());
+        assert!(pool.info().unwrap().min_fee_rate > cheap.size().map(|size| fee_rate(1, size)).unwrap());
+    }
+
+    #[test]
+    fn package_totals_follow_additions_and_removals() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let mut bc = chain(&alice);
+        let mut pool = mempool();
+
+        let parent = spend(&alice, &coinbase(&bc, 0), 0, vec![pay(&bob, 9)], SEQUENCE_FINAL);
+        let child = spend(&bob, &parent, 0, vec![pay(&bob, 7)], SEQUENCE_FINAL);
+        pool.add(&bc, parent.clone()).unwrap();
+        pool.add(&bc, child.clone()).unwrap();
+        let (parent_size, child_size) = (parent.size().unwrap(), child.size().unwrap());
+        let package = pool.get(&parent.id).unwrap().with_descendants;
+        assert_eq!((package.count, package.size, package.fee), (2, parent_size + child_size, 3));
+        let package = pool.get(&child.id).unwrap().with_ancestors;
+        assert_eq!((package.count, package.size, package.fee), (2, parent_size + child_size, 3));
+
+        let reward = Transaction::new_coinbase(alice.get_address(), String::new(), 1).unwrap();
+        bc.mine_block(vec![reward, parent]).unwrap();
+        pool.remove_for_block(&bc.get_block_by_height(1).unwrap());
+        let package = pool.get(&child.id).unwrap().with_ancestors;
+        assert_eq!((package.count, package.size, package.fee), (1, child_size, 2));
+    }
+
+    #[test]
+    fn saved_transactions_are_validated_again_on_load() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let mut bc = chain(&alice);
+        for _ in 0..2 {
+            bc.mine_block(vec![Transaction::new_coinbase(alice.get_address(), String::new(), 0).unwrap()]).unwrap();
+        }
+        let mut pool = mempool();
+
+        let parent = spend(&alice, &coinbase(&bc, 0), 0, vec![pay(&bob, 9)], SEQUENCE_FINAL);
+        let child = spend(&bob, &parent, 0, vec![pay(&bob, 8)], SEQUENCE_FINAL);
+        let double_spent = spend(&alice, &coinbase(&bc, 1), 0, vec![pay(&bob, 9)], SEQUENCE_FINAL);
+        let old = spend(&alice, &coinbase(&bc, 2), 0, vec![pay(&bob, 9)], SEQUENCE_FINAL);
+        for tx in [&parent, &child, &double_spent] {
+            pool.add(&bc, tx.clone()).unwrap();
+        }
+        pool.add_at(&bc, old.clone(), now().unwrap() - 2 * 60 * 60).unwrap();
+
+        let path = std::env::temp_dir().join(format!("mempool-{}.dat", std::process::id()));
+        pool.save(&path).unwrap();
+        let conflict = spend(&alice, &coinbase(&bc, 1), 0, vec![pay(&alice, 9)], SEQUENCE_FINAL);
+        bc.mine_block(vec![Transaction::new_coinbase(alice.get_address(), String::new(), 1).unwrap(), conflict]).unwrap();
+
+        let mut loaded = Mempool::new(DEFAULT_MAX_MEMPOOL_SIZE, 1);
+        loaded.load(&bc, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let ids: Vec<String> = loaded.transactions().into_iter().map(|(tx, _)| tx.id).collect();
+        assert_eq!(ids, [parent.id.clone(), child.id.clone()]);
+        assert_eq!(loaded.get(&child.id).unwrap().with_ancestors.count, 2);
+    }
+}
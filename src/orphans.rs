@@ -0,0 +1,121 @@
+use log::info;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Most transactions held while their inputs are unknown
+pub const MAX_ORPHAN_TXS: usize = 100;
+/// Largest transaction, in bytes, held while its inputs are unknown
+pub const MAX_ORPHAN_TX_SIZE: usize = 100_000;
+/// Most blocks held while their parent is unknown
+pub const MAX_ORPHAN_BLOCKS: usize = 50;
+
+/// Items received before the parents they build on, such as transactions
+/// spending unknown outputs or blocks extending an unknown block. Once
+/// full, the oldest are evicted.
+pub struct OrphanPool<T> {
+    max: usize,
+    orphans: HashMap<String, (T, Vec<String>)>,
+    /// Ids of the orphans waiting for each missing parent
+    by_parent: HashMap<String, HashSet<String>>,
+    /// Ids in the order they were added, for eviction
+    order: VecDeque<String>,
+}
+
+impl<T> OrphanPool<T> {
+    pub fn new(max: usize) -> Self {
+        OrphanPool {
+            max,
+            orphans: HashMap::new(),
+            by_parent: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.orphans.contains_key(id)
+    }
+
+    /// Parents the orphan `id` is waiting for
+    pub fn missing(&self, id: &str) -> Option<&[String]> {
+        self.orphans.get(id).map(|(_, missing)| missing.as_slice())
+    }
+
+    /// Holds `item` until the `missing` parents arrive, evicting the
+    /// oldest orphan if the pool is full
+    pub fn add(&mut self, id: String, missing: Vec<String>, item: T) {
+        if self.orphans.contains_key(&id) {
+            return;
+        }
+        while self.orphans.len() >= self.max {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if self.remove(&oldest).is_some() {
+                info!("Orphan pool full, evicting {}", oldest);
+            }
+        }
+        for parent in &missing {
+            self.by_parent.entry(parent.clone()).or_default().insert(id.clone());
+        }
+        self.order.push_back(id.clone());
+        self.orphans.insert(id, (item, missing));
+    }
+
+    /// Takes out the orphans waiting for `parent`, now that it arrived, to
+    /// be processed again
+    pub fn take_children(&mut self, parent: &str) -> Vec<T> {
+        let ids = self.by_parent.remove(parent).unwrap_or_default();
+        ids.iter().filter_map(|id| self.remove(id)).collect()
+    }
+
+    fn remove(&mut self, id: &str) -> Option<T> {
+        let (item, missing) = self.orphans.remove(id)?;
+        for parent in &missing {
+            if let Some(children) = self.by_parent.get_mut(parent) {
+                children.remove(id);
+                if children.is_empty() {
+                    self.by_parent.remove(parent);
+                }
+            }
+        }
+        self.order.retain(|other| other != id);
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(items: &[&str]) -> Vec<String> {
+        items.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn full_pools_evict_the_oldest() {
+        let mut pool = OrphanPool::new(2);
+        pool.add("a".into(), ids(&["p"]), 1);
+        pool.add("b".into(), ids(&["q"]), 2);
+        pool.add("a".into(), ids(&["p"]), 3);
+        pool.add("c".into(), ids(&["p"]), 4);
+
+        assert!(!pool.contains("a"));
+        assert!(pool.contains("b") && pool.contains("c"));
+        assert_eq!(pool.take_children("p"), [4]);
+        assert_eq!(pool.missing("b"), Some(ids(&["q"]).as_slice()));
+    }
+
+    #[test]
+    fn children_come_out_once_with_their_other_parents_forgotten() {
+        let mut pool = OrphanPool::new(MAX_ORPHAN_TXS);
+        pool.add("a".into(), ids(&["p", "q"]), 1);
+        pool.add("b".into(), ids(&["p"]), 2);
+        pool.add("c".into(), ids(&["q"]), 3);
+
+        let mut children = pool.take_children("p");
+        children.sort();
+        assert_eq!(children, [1, 2]);
+        assert!(pool.take_children("p").is_empty());
+        assert_eq!(pool.take_children("q"), [3]);
+        assert!(pool.by_parent.is_empty() && pool.order.is_empty());
+    }
+}
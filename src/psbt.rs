@@ -0,0 +1,322 @@
+use crate::script::{Op, Script, Timelock};
+use crate::transaction::{sum_values, TXOutput, Transaction, LOCKTIME_THRESHOLD, TX_VERSION};
+use crate::wallets::{encode_address, hash_pub_key, Wallets};
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Partially signed transaction file layout: `MAGIC`, a little-endian
+/// `u32` version, then the bincode-serialized `PartiallySignedTx`
+const MAGIC: &[u8; 4] = b"BCPT";
+const VERSION: u32 = 1;
+
+/// A transaction travelling between an online node and offline signers.
+/// It carries what each input needs to be signed without access to the
+/// chain; unlocking scripts are only built once enough signatures are
+/// collected.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PartiallySignedTx {
+    /// The transaction with empty unlocking scripts
+    pub tx: Transaction,
+    pub inputs: Vec<PsbtInput>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PsbtInput {
+    pub prev_output: TXOutput,
+    /// Script behind a P2SH output, needed to sign and spend it
+    pub redeem_script: Option<Script>,
+    /// Signatures collected so far, by public key
+    pub signatures: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Preimage claiming an HTLC input; without it the input is a refund
+    pub preimage: Option<Vec<u8>>,
+}
+
+impl PartiallySignedTx {
+    pub fn new(tx: Transaction, prev_outputs: Vec<TXOutput>) -> Result<Self> {
+        if tx.vin.len() != prev_outputs.len() {
+            return Err(anyhow!("Expected {} previous outputs, got {}", tx.vin.len(), prev_outputs.len()));
+        }
+        let inputs = prev_outputs.into_iter()
+            .map(|prev_output| PsbtInput {
+                prev_output,
+                redeem_script: None,
+                signatures: BTreeMap::new(),
+                preimage: None,
+            })
+            .collect();
+        Ok(PartiallySignedTx { tx, inputs })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let data = std::fs::read(path)?;
+        if data.len() < 8 || &data[..4] != MAGIC {
+            return Err(anyhow!("Not a partially signed transaction file"));
+        }
+        let version = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        if version != VERSION {
+            return Err(anyhow!("Unsupported transaction file version {}", version));
+        }
+
+        let psbt: PartiallySignedTx = deserialize(&data[8..])?;
+        if psbt.tx.vin.len() != psbt.inputs.len() {
+            return Err(anyhow!("Transaction file is inconsistent: inputs and previous outputs differ"));
+        }
+        Ok(psbt)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend(serialize(self)?);
+        std::fs::write(path, data)?;
+        Ok(())
+    }
+
+    /// Attaches `redeem_script` to the inputs spending its P2SH address.
+    /// A timelock in the script is applied to the transaction's lock time
+    /// or the input sequences, which signatures commit to, so this fails
+    /// once any input is signed. Returns the number of inputs it applies
+    /// to.
+    pub fn add_redeem_script(&mut self, redeem_script: &Script) -> Result<usize> {
+        if self.inputs.iter().any(|input| !input.signatures.is_empty()) {
+            return Err(anyhow!("Cannot add a redeem script after signing; it may change what the signatures cover"));
+        }
+        let p2sh = Script::p2sh(&redeem_script.script_hash()?);
+        let lock = redeem_script.parse_timelock().map(|(lock, _)| lock);
+        let mut count = 0;
+        for idx in 0..self.inputs.len() {
+            if self.inputs[idx].prev_output.script_pubkey != p2sh {
+                continue;
+            }
+            match lock {
+                Some(Timelock::Absolute(lock_time)) => {
+                    let current = self.tx.lock_time;
+                    if current != 0 && (current < LOCKTIME_THRESHOLD) != (lock_time < LOCKTIME_THRESHOLD) {
+                        return Err(anyhow!("Cannot combine height and time locks in one transaction"));
+                    }
+                    self.tx.set_lock_time(current.max(lock_time));
+                }
+                Some(Timelock::Relative(sequence)) => self.tx.vin[idx].sequence = sequence,
+                None => {}
+            }
+            self.inputs[idx].redeem_script = Some(redeem_script.clone());
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Claims the HTLC inputs locked to the hash of `preimage`. Returns
+    /// the number of inputs it applies to.
+    pub fn add_preimage(&mut self, preimage: &[u8]) -> usize {
+        let hash = Sha256::digest(preimage).to_vec();
+        let mut count = 0;
+        for input in &mut self.inputs {
+            let htlc = input.redeem_script.as_ref().and_then(|script| script.parse_htlc());
+            if htlc.is_some_and(|htlc| htlc.hash == hash) {
+                input.preimage = Some(preimage.to_vec());
+                count += 1;
+            }
+        }
+        count
+    }
+
+    fn prev_outputs(&self) -> Vec<TXOutput> {
+        self.inputs.iter().map(|input| input.prev_output.clone()).collect()
+    }
+
+    /// Adds a signature for every input that `wallets` holds a key for,
+    /// including our keys of multisig inputs. Returns the number of
+    /// signatures added.
+    pub fn sign(&mut self, wallets: &Wallets) -> Result<usize> {
+        if wallets.is_locked() {
+            return Err(anyhow!("Wallet is locked. Unlock it with --passphrase to sign"));
+        }
+        // Older signatures do not cover the spent amounts
+        if self.tx.version < TX_VERSION {
+            return Err(anyhow!("Refusing to sign a version {} transaction; create it again", self.tx.version));
+        }
+
+        let prev_outputs = self.prev_outputs();
+        let mut signed = 0;
+        for idx in 0..self.inputs.len() {
+            let input = &self.inputs[idx];
+            let script = match &input.redeem_script {
+                Some(redeem_script) => inner_script(redeem_script),
+                None => input.prev_output.script_pubkey.clone(),
+            };
+            let addresses = match (script.p2pkh_hash(), script.parse_multisig(), script.parse_htlc()) {
+                (Some(hash), _, _) => vec![encode_address(hash)],
+                (None, Some((_, keys)), _) => keys.iter().map(|key| encode_address(&hash_pub_key(key))).collect(),
+                (None, None, Some(htlc)) if input.preimage.is_some() => vec![htlc.recipient_address()],
+                (None, None, Some(htlc)) => vec![htlc.sender_address()],
+                (None, None, None) => Vec::new(),
+            };
+
+            for address in addresses {
+                if let Ok(wallet) = wallets.get_wallet(&address) {
+                    let signature = self.tx.input_signature(idx, wallet, &prev_outputs)?;
+                    self.inputs[idx].signatures.insert(wallet.public_key(), signature);
+                    signed += 1;
+                }
+            }
+        }
+        Ok(signed)
+    }
+
+    /// Unlocking script for input `idx` if enough signatures are present
+    fn unlocking_script(&self, idx: usize) -> Result<Option<Script>> {
+        let input = &self.inputs[idx];
+        if let Some(hash) = input.prev_output.script_pubkey.p2pkh_hash() {
+            return Ok(input.signatures.iter()
+                .find(|(key, _)| hash_pub_key(key) == hash)
+                .map(|(key, sig)| Script(vec![Op::Push(sig.clone()), Op::Push(key.clone())])));
+        }
+
+        let redeem_script = match &input.redeem_script {
+            Some(script) => script,
+            None => return Ok(None),
+        };
+        let script = inner_script(redeem_script);
+        if let Some(hash) = script.p2pkh_hash() {
+            let redeem = serialize(redeem_script)?;
+            return Ok(input.signatures.iter()
+                .find(|(key, _)| hash_pub_key(key) == hash)
+                .map(|(key, sig)| Script(vec![Op::Push(sig.clone()), Op::Push(key.clone()), Op::Push(redeem)])));
+        }
+        if let Some(htlc) = script.parse_htlc() {
+            let (hash, branch) = match &input.preimage {
+                Some(preimage) => (htlc.recipient, vec![Op::Push(preimage.clone()), Op::Push(vec![1])]),
+                None => (htlc.sender, vec![Op::Push(Vec::new())]),
+            };
+            let redeem = serialize(redeem_script)?;
+            return Ok(input.signatures.iter()
+                .find(|(key, _)| hash_pub_key(key) == hash)
+                .map(|(key, sig)| {
+                    let mut ops = vec![Op::Push(sig.clone()), Op::Push(key.clone())];
+                    ops.extend(branch);
+                    ops.push(Op::Push(redeem));
+                    Script(ops)
+                }));
+        }
+        let (required, keys) = match script.parse_multisig() {
+            Some(multisig) => multisig,
+            None => return Ok(None),
+        };
+        let mut ops: Vec<Op> = keys.iter()
+            .filter_map(|key| input.signatures.get(key))
+            .take(required as usize)
+            .map(|sig| Op::Push(sig.clone()))
+            .collect();
+        if ops.len() < required as usize {
+            return Ok(None);
+        }
+        ops.push(Op::Push(serialize(redeem_script)?));
+        Ok(Some(Script(ops)))
+    }
+
+    /// Number of inputs that have all the signatures they need
+    pub fn signed_inputs(&self) -> usize {
+        (0..self.inputs.len())
+            .filter(|idx| matches!(self.unlocking_script(*idx), Ok(Some(_))))
+            .count()
+    }
+
+    pub fn fee(&self) -> Result<i32> {
+        let input_value = sum_values(self.inputs.iter().map(|input| input.prev_output.value))?;
+        let output_value = sum_values(self.tx.vout.iter().map(|out| out.value))?;
+        Ok(input_value - output_value)
+    }
+
+    /// Size of the transaction once `finalize` adds the unlocking scripts,
+    /// for estimating its fee before it is signed
+    pub fn signed_size(&self) -> Result<usize> {
+        let signature = || Op::Push(vec![0; 64]);
+        let key = || Op::Push(vec![0; 32]);
+        let mut tx = self.tx.clone();
+        for (idx, input) in self.inputs.iter().enumerate() {
+            if input.prev_output.script_pubkey.p2pkh_hash().is_some() {
+                tx.vin[idx].script_sig = Script(vec![signature(), key()]);
+                continue;
+            }
+            let redeem_script = input.redeem_script.as_ref()
+                .ok_or_else(|| anyhow!("Input {} needs its redeem script to estimate the size", idx))?;
+            let script = inner_script(redeem_script);
+            let mut ops = if script.p2pkh_hash().is_some() {
+                vec![signature(), key()]
+            } else if script.parse_htlc().is_some() {
+                match &input.preimage {
+                    Some(preimage) => vec![signature(), key(), Op::Push(preimage.clone()), Op::Push(vec![1])],
+                    None => vec![signature(), key(), Op::Push(Vec::new())],
+                }
+            } else if let Some((required, _)) = script.parse_multisig() {
+                (0..required).map(|_| signature()).collect()
+            } else {
+                return Err(anyhow!("Cannot estimate the size of input {}", idx));
+            };
+            ops.push(Op::Push(serialize(redeem_script)?));
+            tx.vin[idx].script_sig = Script(ops);
+        }
+        tx.id = tx.hash()?;
+        tx.size()
+    }
+
+    /// Builds the unlocking scripts and returns the signed transaction.
+    /// Whether its lock times have passed is not checked here since there
+    /// is no chain to check them against.
+    pub fn finalize(&self) -> Result<Transaction> {
+        let mut tx = self.tx.clone();
+        for idx in 0..self.inputs.len() {
+            tx.vin[idx].script_sig = self.unlocking_script(idx)?.ok_or_else(|| anyhow!(
+                "Transaction is not fully signed: {} of {} inputs signed",
+                self.signed_inputs(), self.inputs.len()
+            ))?;
+        }
+        tx.id = tx.hash()?;
+        tx.verify(&self.prev_outputs())
+            .map_err(|e| anyhow!("Transaction is not fully signed: {}", e))?;
+        Ok(tx)
+    }
+}
+
+/// The script a timelocked redeem script guards, or the script itself
+fn inner_script(redeem_script: &Script) -> Script {
+    match redeem_script.parse_timelock() {
+        Some((_, script)) => script,
+        None => redeem_script.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::{pay, wallet};
+    use crate::transaction::{TXInput, SEQUENCE_FINAL};
+
+    #[test]
+    fn redeem_scripts_are_refused_once_signed() {
+        let alice = wallet(1);
+        let mut wallets = Wallets::default();
+        wallets.import_privkey(&alice.to_wif()).unwrap();
+        let redeem = Script::timelock(Timelock::Absolute(100), &Script::p2pkh(&hash_pub_key(&alice.public_key())));
+        let prev_output = TXOutput { value: 10, script_pubkey: Script::p2sh(&redeem.script_hash().unwrap()) };
+        let tx = Transaction {
+            id: String::new(),
+            version: TX_VERSION,
+            vin: vec![TXInput { txid: "00".repeat(32), vout: 0, script_sig: Script::default(), sequence: SEQUENCE_FINAL }],
+            vout: vec![pay(&alice, 9)],
+            lock_time: 0,
+        };
+        let mut psbt = PartiallySignedTx::new(tx, vec![prev_output]).unwrap();
+
+        assert_eq!(psbt.add_redeem_script(&redeem).unwrap(), 1);
+        assert_eq!(psbt.tx.lock_time, 100);
+        let size = psbt.signed_size().unwrap();
+        assert_eq!(psbt.sign(&wallets).unwrap(), 1);
+        assert!(psbt.add_redeem_script(&redeem).is_err());
+        assert_eq!(psbt.finalize().unwrap().size().unwrap(), size);
+    }
+}
@@ -0,0 +1,722 @@
+use crate::transaction::{
+    LOCKTIME_THRESHOLD, SEQUENCE_DISABLE_FLAG, SEQUENCE_FINAL, SEQUENCE_GRANULARITY, SEQUENCE_MASK,
+    SEQUENCE_TYPE_FLAG,
+};
+use crate::wallets::{
+    decode_versioned_address, encode_versioned_address, hash_pub_key, ADDRESS_VERSION,
+    MULTISIG_ADDRESS_VERSION,
+};
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// Largest element that can be pushed or left on the stack
+pub const MAX_ELEMENT_SIZE: usize = 1024;
+const MAX_STACK_SIZE: usize = 1000;
+/// Upper bound on the operations run for one input, scripts included
+const MAX_OPS: usize = 500;
+/// Largest payload of a `Script::data` output
+pub const MAX_DATA_SIZE: usize = 80;
+/// Upper bound on the keys of a multisig policy
+pub const MAX_MULTISIG_KEYS: usize = 16;
+const PUB_KEY_LEN: usize = 32;
+const HASH160_LEN: usize = 20;
+
+/// Script operations. Data and numbers are pushed with `Push`; numbers
+/// are little-endian and unsigned.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Op {
+    Push(Vec<u8>),
+    Dup,
+    Drop,
+    Hash160,
+    Sha256,
+    Equal,
+    EqualVerify,
+    Verify,
+    /// Pops a public key and a signature and pushes whether the
+    /// signature is valid for the spending transaction
+    CheckSig,
+    /// Pops n, n keys, m and m signatures (in key order) and pushes
+    /// whether all m signatures are valid
+    CheckMultisig,
+    /// Fails unless the transaction lock time is at least the height or
+    /// time on top of the stack, which is left in place, and is enforced
+    CheckLockTime,
+    /// Fails unless the input sequence carries a relative lock of at
+    /// least the one on top of the stack, which is left in place
+    CheckSequence,
+    /// Pops a value and runs the ops up to the matching `Else` or `EndIf`
+    /// if it is true, and those after `Else` otherwise
+    If,
+    Else,
+    EndIf,
+    /// Fails, making the output provably unspendable
+    Return,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Script(pub Vec<Op>);
+
+/// What a script can learn about the transaction spending it
+pub struct ScriptContext<'a> {
+    /// Hash signed by the input being checked
+    pub sighash: &'a [u8],
+    pub version: i32,
+    pub lock_time: u32,
+    /// Sequence of the input being checked
+    pub sequence: u32,
+}
+
+/// Lock placed in front of a script by `Script::timelock`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timelock {
+    /// Spendable from a block height, or a unix time from
+    /// `LOCKTIME_THRESHOLD` on
+    Absolute(u32),
+    /// Spendable once the output is as old as the relative lock of this
+    /// input sequence
+    Relative(u32),
+}
+
+impl fmt::Display for Timelock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Timelock::Absolute(height) if height < LOCKTIME_THRESHOLD => write!(f, "until height {}", height),
+            Timelock::Absolute(time) => write!(f, "until time {}", time),
+            Timelock::Relative(sequence) if sequence & SEQUENCE_TYPE_FLAG != 0 => {
+                write!(f, "for {}s after funding", ((sequence & SEQUENCE_MASK) as u64) << SEQUENCE_GRANULARITY)
+            }
+            Timelock::Relative(sequence) => write!(f, "for {} blocks after funding", sequence & SEQUENCE_MASK),
+        }
+    }
+}
+
+impl Script {
+    /// Pay to public key hash: `DUP HASH160 <hash> EQUALVERIFY CHECKSIG`,
+    /// unlocked by `<sig> <pub key>`
+    pub fn p2pkh(pub_key_hash: &[u8]) -> Script {
+        Script(vec![
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(pub_key_hash.to_vec()),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// Pay to script hash: `HASH160 <hash> EQUAL`, unlocked by the inputs
+    /// of the redeem script followed by the serialized redeem script
+    pub fn p2sh(script_hash: &[u8]) -> Script {
+        Script(vec![Op::Hash160, Op::Push(script_hash.to_vec()), Op::Equal])
+    }
+
+    /// `<m> <key>... <n> CHECKMULTISIG`
+    pub fn multisig(required: u8, pub_keys: &[Vec<u8>]) -> Script {
+        let mut ops = vec![push_int(required as i64)];
+        ops.extend(pub_keys.iter().map(|key| Op::Push(key.clone())));
+        ops.push(push_int(pub_keys.len() as i64));
+        ops.push(Op::CheckMultisig);
+        Script(ops)
+    }
+
+    /// `<lock> CHECKLOCKTIME|CHECKSEQUENCE DROP` followed by `script`, which
+    /// can only be satisfied once the lock has passed
+    pub fn timelock(lock: Timelock, script: &Script) -> Script {
+        let (value, op) = match lock {
+            Timelock::Absolute(lock_time) => (lock_time, Op::CheckLockTime),
+            Timelock::Relative(sequence) => (sequence, Op::CheckSequence),
+        };
+        let mut ops = vec![push_int(value as i64), op, Op::Drop];
+        ops.extend(script.0.iter().cloned());
+        Script(ops)
+    }
+
+    /// Lock and inner script of a `timelock` script
+    pub fn parse_timelock(&self) -> Option<(Timelock, Script)> {
+        let (Op::Push(value), op, Op::Drop) = (self.0.first()?, self.0.get(1)?, self.0.get(2)?) else {
+            return None;
+        };
+        let value = u32::try_from(decode_int(value).ok()?).ok()?;
+        let lock = match op {
+            Op::CheckLockTime => Timelock::Absolute(value),
+            Op::CheckSequence => Timelock::Relative(value),
+            _ => return None,
+        };
+        Some((lock, Script(self.0[3..].to_vec())))
+    }
+
+    /// `RETURN <data>`: carries data in an output that can never be
+    /// spent, and so is never added to the UTXO set
+    pub fn data(data: &[u8]) -> Result<Script> {
+        if data.len() > MAX_DATA_SIZE {
+            return Err(anyhow!("Data outputs carry at most {} bytes, got {}", MAX_DATA_SIZE, data.len()));
+        }
+        Ok(Script(vec![Op::Return, Op::Push(data.to_vec())]))
+    }
+
+    /// Whether the script fails before anything can satisfy it
+    pub fn is_unspendable(&self) -> bool {
+        self.0.first() == Some(&Op::Return)
+    }
+
+    /// Payload of a `data` script
+    pub fn data_payload(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [Op::Return, Op::Push(data)] => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn from_address(address: &str) -> Result<Script> {
+        match decode_versioned_address(address)? {
+            (ADDRESS_VERSION, hash) if hash.len() == HASH160_LEN => Ok(Script::p2pkh(&hash)),
+            (MULTISIG_ADDRESS_VERSION, hash) if hash.len() == HASH160_LEN => Ok(Script::p2sh(&hash)),
+            (version, _) => Err(anyhow!("Unknown address version {:#04x}", version)),
+        }
+    }
+
+    /// Address of standard scripts, `None` for anything else
+    pub fn address(&self) -> Option<String> {
+        if let Some(hash) = self.p2pkh_hash() {
+            return Some(encode_versioned_address(ADDRESS_VERSION, hash));
+        }
+        match self.0.as_slice() {
+            [Op::Hash160, Op::Push(hash), Op::Equal] if hash.len() == HASH160_LEN => {
+                Some(encode_versioned_address(MULTISIG_ADDRESS_VERSION, hash))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn p2pkh_hash(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [Op::Dup, Op::Hash160, Op::Push(hash), Op::EqualVerify, Op::CheckSig] if hash.len() == HASH160_LEN => Some(hash),
+            _ => None,
+        }
+    }
+
+    fn is_p2sh(&self) -> bool {
+        matches!(self.0.as_slice(), [Op::Hash160, Op::Push(hash), Op::Equal] if hash.len() == HASH160_LEN)
+    }
+
+    /// Required signatures and keys of a `multisig` script
+    pub fn parse_multisig(&self) -> Option<(u8, Vec<Vec<u8>>)> {
+        let ops = self.0.as_slice();
+        let (Op::Push(m), Op::Push(n), Some(Op::CheckMultisig)) = (ops.first()?, ops.get(ops.len().checked_sub(2)?)?, ops.last()) else {
+            return None;
+        };
+        let keys: Vec<Vec<u8>> = ops[1..ops.len() - 2].iter()
+            .map(|op| match op {
+                Op::Push(key) => Some(key.clone()),
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+        let m = decode_int(m).ok()?;
+        if decode_int(n).ok()? != keys.len() as i64 || m <= 0 || m > keys.len() as i64 {
+            return None;
+        }
+        Some((m as u8, keys))
+    }
+
+    /// Terms of an `Htlc::redeem_script` script
+    pub fn parse_htlc(&self) -> Option<Htlc> {
+        let [Op::If, Op::Sha256, Op::Push(hash), Op::EqualVerify, Op::Dup, Op::Hash160, Op::Push(recipient),
+            Op::Else, Op::Push(timeout), Op::CheckLockTime, Op::Drop, Op::Dup, Op::Hash160, Op::Push(sender),
+            Op::EndIf, Op::EqualVerify, Op::CheckSig] = self.0.as_slice()
+        else {
+            return None;
+        };
+        Some(Htlc {
+            hash: hash.clone(),
+            recipient: recipient.clone(),
+            sender: sender.clone(),
+            timeout: u32::try_from(decode_int(timeout).ok()?).ok()?,
+        })
+    }
+
+    /// Hash committed to by a P2SH script for this redeem script
+    pub fn script_hash(&self) -> Result<Vec<u8>> {
+        Ok(hash_pub_key(&serialize(self)?))
+    }
+
+    fn is_push_only(&self) -> bool {
+        self.0.iter().all(|op| matches!(op, Op::Push(_)))
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ops: Vec<String> = self.0.iter()
+            .map(|op| match op {
+                Op::Push(data) => format!("<{}>", hex::encode(data)),
+                other => format!("{:?}", other).to_uppercase(),
+            })
+            .collect();
+        write!(f, "{}", ops.join(" "))
+    }
+}
+
+/// Runs `script_sig` and then `script_pubkey` on the same stack. The spend
+/// is valid if neither fails and the top of the stack is true. For P2SH
+/// outputs the last item pushed by `script_sig` is then run as a script.
+pub fn verify_script(script_sig: &Script, script_pubkey: &Script, ctx: &ScriptContext) -> Result<()> {
+    if !script_sig.is_push_only() {
+        return Err(anyhow!("unlocking script may only push data"));
+    }
+
+    let mut machine = Machine { stack: Vec::new(), ops: 0, ctx };
+    machine.run(script_sig)?;
+    let redeem_stack = machine.stack.clone();
+    machine.run(script_pubkey)?;
+    machine.check_true()?;
+
+    if script_pubkey.is_p2sh() {
+        let mut stack = redeem_stack;
+        let redeem = stack.pop().ok_or_else(|| anyhow!("missing redeem script"))?;
+        let redeem: Script = deserialize(&redeem).map_err(|_| anyhow!("malformed redeem script"))?;
+        machine.stack = stack;
+        machine.run(&redeem)?;
+        machine.check_true()?;
+    }
+    Ok(())
+}
+
+struct Machine<'a> {
+    stack: Vec<Vec<u8>>,
+    ops: usize,
+    ctx: &'a ScriptContext<'a>,
+}
+
+impl Machine<'_> {
+    fn run(&mut self, script: &Script) -> Result<()> {
+        // Whether each enclosing IF runs its current branch
+        let mut branches: Vec<bool> = Vec::new();
+        for op in &script.0 {
+            self.ops += 1;
+            if self.ops > MAX_OPS {
+                return Err(anyhow!("script exceeds {} operations", MAX_OPS));
+            }
+            let executing = branches.iter().all(|taken| *taken);
+            match op {
+                Op::If => {
+                    let taken = executing && is_true(&self.pop()?);
+                    branches.push(taken);
+                }
+                Op::Else => {
+                    let taken = branches.last_mut().ok_or_else(|| anyhow!("ELSE without IF"))?;
+                    *taken = !*taken;
+                }
+                Op::EndIf => {
+                    branches.pop().ok_or_else(|| anyhow!("ENDIF without IF"))?;
+                }
+                _ if executing => self.step(op)?,
+                _ => {}
+            }
+            if self.stack.len() > MAX_STACK_SIZE {
+                return Err(anyhow!("stack overflow"));
+            }
+        }
+        if !branches.is_empty() {
+            return Err(anyhow!("IF without ENDIF"));
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, op: &Op) -> Result<()> {
+        match op {
+            Op::Push(data) => {
+                if data.len() > MAX_ELEMENT_SIZE {
+                    return Err(anyhow!("pushed element exceeds {} bytes", MAX_ELEMENT_SIZE));
+                }
+                self.stack.push(data.clone());
+            }
+            Op::Dup => {
+                let top = self.peek()?.clone();
+                self.stack.push(top);
+            }
+            Op::Drop => {
+                self.pop()?;
+            }
+            Op::Hash160 => {
+                let data = self.pop()?;
+                self.stack.push(hash_pub_key(&data));
+            }
+            Op::Sha256 => {
+                let data = self.pop()?;
+                self.stack.push(Sha256::digest(&data).to_vec());
+            }
+            Op::Equal => {
+                let equal = self.pop()? == self.pop()?;
+                self.push_bool(equal);
+            }
+            Op::EqualVerify => {
+                if self.pop()? != self.pop()? {
+                    return Err(anyhow!("EQUALVERIFY failed"));
+                }
+            }
+            Op::Verify => {
+                if !is_true(&self.pop()?) {
+                    return Err(anyhow!("VERIFY failed"));
+                }
+            }
+            Op::CheckSig => {
+                let pub_key = self.pop()?;
+                let sig = self.pop()?;
+                let valid = check_signature(&pub_key, &sig, self.ctx.sighash);
+                self.push_bool(valid);
+            }
+            Op::CheckMultisig => {
+                let n = self.pop_count(MAX_MULTISIG_KEYS)?;
+                let mut keys = (0..n).map(|_| self.pop()).collect::<Result<Vec<_>>>()?;
+                keys.reverse();
+                let m = self.pop_count(n)?;
+                let mut sigs = (0..m).map(|_| self.pop()).collect::<Result<Vec<_>>>()?;
+                sigs.reverse();
+
+                // Each signature must match a later key than the one before
+                let mut keys = keys.iter();
+                let valid = sigs.iter().all(|sig| {
+                    keys.by_ref().any(|key| check_signature(key, sig, self.ctx.sighash))
+                });
+                self.push_bool(valid);
+            }
+            Op::CheckLockTime => {
+                let lock_time = decode_int(self.peek()?)?;
+                let lock_time = u32::try_from(lock_time).map_err(|_| anyhow!("lock time out of range"))?;
+                if (lock_time < LOCKTIME_THRESHOLD) != (self.ctx.lock_time < LOCKTIME_THRESHOLD) {
+                    return Err(anyhow!("lock time {} and transaction lock time differ in kind", lock_time));
+                }
+                if lock_time > self.ctx.lock_time || self.ctx.sequence == SEQUENCE_FINAL {
+                    return Err(anyhow!("output is locked {}", Timelock::Absolute(lock_time)));
+                }
+            }
+            Op::CheckSequence => {
+                let sequence = decode_int(self.peek()?)?;
+                let sequence = u32::try_from(sequence).map_err(|_| anyhow!("sequence out of range"))?;
+                if sequence & SEQUENCE_DISABLE_FLAG != 0 {
+                    return Ok(());
+                }
+                let unlocked = self.ctx.version >= 2
+                    && self.ctx.sequence & SEQUENCE_DISABLE_FLAG == 0
+                    && sequence & SEQUENCE_TYPE_FLAG == self.ctx.sequence & SEQUENCE_TYPE_FLAG
+                    && sequence & SEQUENCE_MASK <= self.ctx.sequence & SEQUENCE_MASK;
+                if !unlocked {
+                    return Err(anyhow!("output is locked {}", Timelock::Relative(sequence)));
+                }
+            }
+            Op::Return => return Err(anyhow!("output is unspendable")),
+            Op::If | Op::Else | Op::EndIf => unreachable!("flow control is handled by run"),
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Vec<u8>> {
+        self.stack.pop().ok_or_else(|| anyhow!("stack underflow"))
+    }
+
+    fn peek(&self) -> Result<&Vec<u8>> {
+        self.stack.last().ok_or_else(|| anyhow!("stack underflow"))
+    }
+
+    fn pop_count(&mut self, max: usize) -> Result<usize> {
+        let count = decode_int(&self.pop()?)?;
+        if count < 0 || count as usize > max {
+            return Err(anyhow!("count {} out of range", count));
+        }
+        Ok(count as usize)
+    }
+
+    fn push_bool(&mut self, value: bool) {
+        self.stack.push(if value { vec![1] } else { Vec::new() });
+    }
+
+    fn check_true(&self) -> Result<()> {
+        match self.stack.last() {
+            Some(top) if is_true(top) => Ok(()),
+            _ => Err(anyhow!("script evaluated to false")),
+        }
+    }
+}
+
+fn is_true(data: &[u8]) -> bool {
+    data.iter().any(|byte| *byte != 0)
+}
+
+fn check_signature(pub_key: &[u8], sig: &[u8], sighash: &[u8]) -> bool {
+    pub_key.len() == PUB_KEY_LEN
+        && UnparsedPublicKey::new(&ED25519, pub_key).verify(sighash, sig).is_ok()
+}
+
+/// Shortest little-endian encoding of a non-negative number
+pub fn push_int(value: i64) -> Op {
+    let mut bytes = value.to_le_bytes().to_vec();
+    while bytes.last() == Some(&0) {
+        bytes.pop();
+    }
+    Op::Push(bytes)
+}
+
+fn decode_int(data: &[u8]) -> Result<i64> {
+    if data.len() > 8 {
+        return Err(anyhow!("number too long"));
+    }
+    let mut bytes = [0u8; 8];
+    bytes[..data.len()].copy_from_slice(data);
+    let value = i64::from_le_bytes(bytes);
+    if value < 0 {
+        return Err(anyhow!("number out of range"));
+    }
+    Ok(value)
+}
+
+/// m-of-n spending policy, paid to through the P2SH address of its
+/// multisig script. Keys are kept sorted so cosigners listing them in
+/// any order arrive at the same address.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MultisigPolicy {
+    pub required: u8,
+    pub pub_keys: Vec<Vec<u8>>,
+}
+
+impl MultisigPolicy {
+    pub fn new(required: u8, mut pub_keys: Vec<Vec<u8>>) -> Result<Self> {
+        if pub_keys.iter().any(|key| key.len() != PUB_KEY_LEN) {
+            return Err(anyhow!("Multisig keys must be 32-byte Ed25519 public keys"));
+        }
+        pub_keys.sort();
+        pub_keys.dedup();
+        if pub_keys.is_empty() || pub_keys.len() > MAX_MULTISIG_KEYS {
+            return Err(anyhow!("A multisig policy needs 1 to {} distinct keys", MAX_MULTISIG_KEYS));
+        }
+        if required == 0 || required as usize > pub_keys.len() {
+            return Err(anyhow!("Cannot require {} of {} signatures", required, pub_keys.len()));
+        }
+        Ok(MultisigPolicy { required, pub_keys })
+    }
+
+    pub fn redeem_script(&self) -> Script {
+        Script::multisig(self.required, &self.pub_keys)
+    }
+}
+
+/// Hash time-locked contract: pays `recipient` against the preimage of
+/// `hash`, or refunds `sender` once the `timeout` lock time has passed.
+/// Both parties are identified by public key hash.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Htlc {
+    pub hash: Vec<u8>,
+    pub recipient: Vec<u8>,
+    pub sender: Vec<u8>,
+    pub timeout: u32,
+}
+
+impl Htlc {
+    pub fn new(hash: Vec<u8>, recipient: &str, sender: &str, timeout: u32) -> Result<Self> {
+        if hash.len() != 32 {
+            return Err(anyhow!("HTLC hash must be a 32-byte SHA-256 hash"));
+        }
+        let key_hash = |address: &str| -> Result<Vec<u8>> {
+            Script::from_address(address)?.p2pkh_hash()
+                .map(|hash| hash.to_vec())
+                .ok_or_else(|| anyhow!("{} is not a single-key address", address))
+        };
+        Ok(Htlc {
+            hash,
+            recipient: key_hash(recipient)?,
+            sender: key_hash(sender)?,
+            timeout,
+        })
+    }
+
+    /// `IF SHA256 <hash> EQUALVERIFY DUP HASH160 <recipient> ELSE <timeout>
+    /// CHECKLOCKTIME DROP DUP HASH160 <sender> ENDIF EQUALVERIFY CHECKSIG`,
+    /// claimed by `<sig> <pub key> <preimage> 1` and refunded by
+    /// `<sig> <pub key> 0`
+    pub fn redeem_script(&self) -> Script {
+        Script(vec![
+            Op::If,
+            Op::Sha256,
+            Op::Push(self.hash.clone()),
+            Op::EqualVerify,
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(self.recipient.clone()),
+            Op::Else,
+            push_int(self.timeout as i64),
+            Op::CheckLockTime,
+            Op::Drop,
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(self.sender.clone()),
+            Op::EndIf,
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    pub fn recipient_address(&self) -> String {
+        encode_versioned_address(ADDRESS_VERSION, &self.recipient)
+    }
+
+    pub fn sender_address(&self) -> String {
+        encode_versioned_address(ADDRESS_VERSION, &self.sender)
+    }
+
+    /// Preimage revealed by an unlocking script that claims an HTLC
+    pub fn revealed_preimage(script_sig: &Script) -> Option<Vec<u8>> {
+        let [Op::Push(_), Op::Push(_), Op::Push(preimage), Op::Push(branch), Op::Push(redeem)] = script_sig.0.as_slice() else {
+            return None;
+        };
+        let htlc = deserialize::<Script>(redeem).ok()?.parse_htlc()?;
+        (is_true(branch) && Sha256::digest(preimage).as_slice() == htlc.hash).then(|| preimage.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::wallet;
+    use crate::transaction::TX_VERSION;
+
+    const SIGHASH: &[u8] = b"spending transaction";
+
+    fn ctx(version: i32, lock_time: u32, sequence: u32) -> ScriptContext<'static> {
+        ScriptContext { sighash: SIGHASH, version, lock_time, sequence }
+    }
+
+    fn run(script_sig: Vec<Op>, script_pubkey: &Script, ctx: &ScriptContext) -> Result<()> {
+        verify_script(&Script(script_sig), script_pubkey, ctx)
+    }
+
+    #[test]
+    fn p2pkh_needs_the_key_and_a_signature_by_it() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let lock = Script::p2pkh(&hash_pub_key(&alice.public_key()));
+        let ctx = ctx(TX_VERSION, 0, SEQUENCE_FINAL);
+
+        let sig = alice.sign(SIGHASH);
+        assert!(run(vec![Op::Push(sig.clone()), Op::Push(alice.public_key())], &lock, &ctx).is_ok());
+        // Bob's key does not hash to the locked one
+        assert!(run(vec![Op::Push(bob.sign(SIGHASH)), Op::Push(bob.public_key())], &lock, &ctx).is_err());
+        // Signed over something else
+        let other = alice.sign(b"another transaction");
+        assert!(run(vec![Op::Push(other), Op::Push(alice.public_key())], &lock, &ctx).is_err());
+        // Unlocking scripts may only push
+        assert!(run(vec![Op::Push(sig), Op::Push(alice.public_key()), Op::Dup, Op::Drop], &lock, &ctx).is_err());
+    }
+
+    #[test]
+    fn multisig_needs_m_signatures_in_key_order() {
+        let wallets = [wallet(1), wallet(2), wallet(3)];
+        let policy = MultisigPolicy::new(2, wallets.iter().map(|w| w.public_key()).collect()).unwrap();
+        let signer = |key: &Vec<u8>| wallets.iter().find(|w| w.public_key() == *key).unwrap();
+        let sigs: Vec<Vec<u8>> = policy.pub_keys.iter().map(|key| signer(key).sign(SIGHASH)).collect();
+        let lock = policy.redeem_script();
+        let ctx = ctx(TX_VERSION, 0, SEQUENCE_FINAL);
+
+        for (a, b) in [(0, 1), (0, 2), (1, 2)] {
+            assert!(run(vec![Op::Push(sigs[a].clone()), Op::Push(sigs[b].clone())], &lock, &ctx).is_ok());
+        }
+        // Out of key order, the same signature twice, or one short
+        assert!(run(vec![Op::Push(sigs[1].clone()), Op::Push(sigs[0].clone())], &lock, &ctx).is_err());
+        assert!(run(vec![Op::Push(sigs[0].clone()), Op::Push(sigs[0].clone())], &lock, &ctx).is_err());
+        assert!(run(vec![Op::Push(sigs[0].clone())], &lock, &ctx).is_err());
+    }
+
+    #[test]
+    fn p2sh_runs_the_redeem_script() {
+        let (alice, bob) = (wallet(1), wallet(2));
+        let policy = MultisigPolicy::new(1, vec![alice.public_key(), bob.public_key()]).unwrap();
+        let redeem = policy.redeem_script();
+        let lock = Script::p2sh(&redeem.script_hash().unwrap());
+        let ctx = ctx(TX_VERSION, 0, SEQUENCE_FINAL);
+        let redeem_bytes = serialize(&redeem).unwrap();
+
+        assert!(run(vec![Op::Push(alice.sign(SIGHASH)), Op::Push(redeem_bytes.clone())], &lock, &ctx).is_ok());
+        // The hash matches, but the redeem script then fails
+        assert!(run(vec![Op::Push(wallet(3).sign(SIGHASH)), Op::Push(redeem_bytes)], &lock, &ctx).is_err());
+        // A different script does not hash to the locked one
+        let other = serialize(&MultisigPolicy::new(1, vec![alice.public_key()]).unwrap().redeem_script()).unwrap();
+        assert!(run(vec![Op::Push(alice.sign(SIGHASH)), Op::Push(other)], &lock, &ctx).is_err());
+    }
+
+    #[test]
+    fn check_lock_time_compares_kind_value_and_sequence() {
+        let height_lock = Script(vec![push_int(100), Op::CheckLockTime]);
+        assert!(run(Vec::new(), &height_lock, &ctx(TX_VERSION, 100, 0)).is_ok());
+        assert!(run(Vec::new(), &height_lock, &ctx(TX_VERSION, 99, 0)).is_err());
+        // A final sequence would let the transaction skip its lock time
+        assert!(run(Vec::new(), &height_lock, &ctx(TX_VERSION, 100, SEQUENCE_FINAL)).is_err());
+        // A time does not satisfy a height, nor the other way around
+        assert!(run(Vec::new(), &height_lock, &ctx(TX_VERSION, LOCKTIME_THRESHOLD + 100, 0)).is_err());
+        let time_lock = Script(vec![push_int(LOCKTIME_THRESHOLD as i64 + 100), Op::CheckLockTime]);
+        assert!(run(Vec::new(), &time_lock, &ctx(TX_VERSION, LOCKTIME_THRESHOLD + 100, 0)).is_ok());
+        assert!(run(Vec::new(), &time_lock, &ctx(TX_VERSION, 100, 0)).is_err());
+    }
+
+    #[test]
+    fn check_sequence_compares_kind_value_and_version() {
+        let blocks = Script(vec![push_int(10), Op::CheckSequence]);
+        assert!(run(Vec::new(), &blocks, &ctx(TX_VERSION, 0, 10)).is_ok());
+        assert!(run(Vec::new(), &blocks, &ctx(TX_VERSION, 0, 9)).is_err());
+        // Relative locks need version 2 and an enabled input sequence
+        assert!(run(Vec::new(), &blocks, &ctx(1, 0, 10)).is_err());
+        assert!(run(Vec::new(), &blocks, &ctx(TX_VERSION, 0, SEQUENCE_FINAL)).is_err());
+        // Seconds do not satisfy blocks
+        assert!(run(Vec::new(), &blocks, &ctx(TX_VERSION, 0, SEQUENCE_TYPE_FLAG | 10)).is_err());
+        let seconds = Script(vec![push_int((SEQUENCE_TYPE_FLAG | 10) as i64), Op::CheckSequence]);
+        assert!(run(Vec::new(), &seconds, &ctx(TX_VERSION, 0, SEQUENCE_TYPE_FLAG | 10)).is_ok());
+        // A lock with the disable flag set is no lock
+        let disabled = Script(vec![push_int(SEQUENCE_DISABLE_FLAG as i64 | 10), Op::CheckSequence]);
+        assert!(run(Vec::new(), &disabled, &ctx(1, 0, SEQUENCE_FINAL)).is_ok());
+    }
+
+    #[test]
+    fn if_else_branches_nest() {
+        let script = |outer: u8, inner: u8| Script(vec![
+            Op::Push(vec![inner]),
+            Op::Push(vec![outer]),
+            Op::If,
+            Op::If,
+            Op::Push(b"a".to_vec()),
+            Op::Else,
+            Op::Push(b"b".to_vec()),
+            Op::EndIf,
+            Op::Else,
+            Op::Drop,
+            Op::Push(b"c".to_vec()),
+            Op::EndIf,
+            Op::Push(b"b".to_vec()),
+            Op::Equal,
+        ]);
+        let ctx = ctx(TX_VERSION, 0, SEQUENCE_FINAL);
+        assert!(run(Vec::new(), &script(1, 0), &ctx).is_ok());
+        assert!(run(Vec::new(), &script(1, 1), &ctx).is_err());
+        assert!(run(Vec::new(), &script(0, 0), &ctx).is_err());
+
+        for unbalanced in [vec![push_int(1), Op::If], vec![push_int(1), Op::Else], vec![push_int(1), Op::EndIf]] {
+            assert!(run(Vec::new(), &Script(unbalanced), &ctx).is_err());
+        }
+    }
+
+    #[test]
+    fn limits_operations_and_stack_size() {
+        let ctx = ctx(TX_VERSION, 0, SEQUENCE_FINAL);
+        let ops = |count: usize| {
+            let mut ops = vec![push_int(1)];
+            ops.extend([Op::Dup, Op::Drop].iter().cycle().take(count - 1).cloned());
+            Script(ops)
+        };
+        assert!(run(Vec::new(), &ops(MAX_OPS - 1), &ctx).is_ok());
+        assert!(run(Vec::new(), &ops(MAX_OPS + 1), &ctx).is_err());
+
+        // Fewer operations than items can never fill the stack from empty
+        let mut machine = Machine { stack: vec![vec![1]; MAX_STACK_SIZE], ops: 0, ctx: &ctx };
+        assert!(machine.run(&Script(vec![Op::Drop, Op::Dup])).is_ok());
+        assert!(machine.run(&Script(vec![Op::Dup])).is_err());
+    }
+}
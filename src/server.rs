@@ -1,42 +1,591 @@
-use crate::utxoset::UTXOSet;
-use anyhow::Result;
-use log::info;
+use crate::block::{Block, TARGET_BITS};
+use crate::mempool::{Mempool, MempoolInfo, MEMPOOL_FILE};
+use crate::orphans::{OrphanPool, MAX_ORPHAN_BLOCKS, MAX_ORPHAN_TXS, MAX_ORPHAN_TX_SIZE};
+use crate::script::Script;
+use crate::transaction::{sum_values, TXOutput, Transaction};
+use crate::utxoset::{Coin, UTXOSet};
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
-pub fn start_miner_node(port: u16, miner_addr: &str, utxo_set: UTXOSet) -> Result<()> {
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
-    
-    let miner_addr = miner_addr.to_string();
-    
-    for stream in listener.incoming() {
-        let stream = stream?;
-        let utxo_set = utxo_set.clone();
-        let miner_addr = miner_addr.clone();
-        
-        thread::spawn(move || {
-            handle_connection(stream, &utxo_set, Some(miner_addr));
-        });
-    }
-    
+/// Node used by commands that talk to the network when none is given
+pub const DEFAULT_NODE: &str = "127.0.0.1:3000";
+/// Upper bound on a single message, so a bad length cannot make us
+/// allocate unbounded memory
+const MAX_MESSAGE_SIZE: u32 = 32 * 1024 * 1024;
+/// Most bytes of mempool transactions a mined block takes
+pub const BLOCK_TEMPLATE_SIZE: usize = 1_000_000;
+/// How often a running node saves its mempool, besides on shutdown
+const MEMPOOL_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a node waits for a peer to answer a request for missing
+/// parents
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Messages are sent as a little-endian `u32` length followed by the
+/// bincode-serialized `Message`
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Message {
+    /// Submits a transaction to the node's mempool
+    Tx(Transaction),
+    TxAccepted(String),
+    Rejected(String),
+    /// Asks for a transaction in the node's mempool by id
+    GetMempoolTx(String),
+    MempoolTx(Option<Transaction>),
+    /// Asks for the fee rate that gets a transaction confirmed within a
+    /// number of blocks
+    EstimateFee(u32),
+    FeeEstimate(Option<i64>),
+    GetMempoolInfo,
+    MempoolInfo(MempoolInfo),
+    /// Submits a block to extend the node's chain
+    Block(Block),
+    BlockAccepted(String),
+    /// Asks for a block by hash
+    GetBlock(String),
+    BlockData(Option<Block>),
+    /// The submitted item is held until the listed parents arrive
+    Orphaned(Vec<String>),
+    /// Asks for the unspent outputs locked by a script that no mempool
+    /// transaction spends yet
+    GetUtxos(Script),
+    Utxos(Vec<Coin>),
+    /// Asks for outputs of the UTXO set by txid and index, whether
+    /// mempool transactions spend them or not
+    GetTxOuts(Vec<(String, i32)>),
+    TxOuts(Vec<Option<TXOutput>>),
+}
+
+pub fn send_message(stream: &mut TcpStream, message: &Message) -> Result<()> {
+    let data = serialize(message)?;
+    stream.write_all(&(data.len() as u32).to_le_bytes())?;
+    stream.write_all(&data)?;
+    stream.flush()?;
     Ok(())
 }
 
-pub fn start_full_node(port: u16, utxo_set: UTXOSet) -> Result<()> {
+pub fn read_message(stream: &mut TcpStream) -> Result<Message> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    if len > MAX_MESSAGE_SIZE {
+        return Err(anyhow!("Message of {} bytes exceeds the limit", len));
+    }
+
+    let mut data = vec![0u8; len as usize];
+    stream.read_exact(&mut data)?;
+    Ok(deserialize(&data)?)
+}
+
+/// Sends `message` to the node at `node` and returns its reply. Requests
+/// the node makes in between for parents of what it was sent are
+/// answered with `serve`.
+fn submit(node: &str, message: &Message, serve: &dyn Fn(&Message) -> Message) -> Result<Message> {
+    let mut stream = TcpStream::connect(node)
+        .map_err(|e| anyhow!("Could not reach node at {}: {}", node, e))?;
+    send_message(&mut stream, message)?;
+    loop {
+        match read_message(&mut stream)? {
+            request @ (Message::GetMempoolTx(_) | Message::GetBlock(_)) => {
+                send_message(&mut stream, &serve(&request))?;
+            }
+            reply => return Ok(reply),
+        }
+    }
+}
+
+/// Answers parent requests with nothing, for clients that keep no
+/// mempool
+fn serve_nothing(request: &Message) -> Message {
+    match request {
+        Message::GetBlock(_) => Message::BlockData(None),
+        _ => Message::MempoolTx(None),
+    }
+}
+
+/// Sends `tx` to the node at `node` and waits for it to be accepted
+/// into the mempool. Returns the txid.
+pub fn broadcast_transaction(node: &str, tx: &Transaction) -> Result<String> {
+    match submit(node, &Message::Tx(tx.clone()), &serve_nothing)? {
+        Message::TxAccepted(txid) => Ok(txid),
+        Message::Rejected(reason) => Err(anyhow!("Node rejected the transaction: {}", reason)),
+        Message::Orphaned(missing) => Err(anyhow!(
+            "Node holds the transaction until it sees transactions {}",
+            missing.join(", ")
+        )),
+        other => Err(anyhow!("Unexpected reply from node: {:?}", other)),
+    }
+}
+
+/// Fetches an unconfirmed transaction from the mempool of the node at
+/// `node`
+pub fn fetch_mempool_transaction(node: &str, txid: &str) -> Result<Transaction> {
+    let mut stream = TcpStream::connect(node)
+        .map_err(|e| anyhow!("Could not reach node at {}: {}", node, e))?;
+    send_message(&mut stream, &Message::GetMempoolTx(txid.to_string()))?;
+    match read_message(&mut stream)? {
+        Message::MempoolTx(Some(tx)) => Ok(tx),
+        Message::MempoolTx(None) => Err(anyhow!("Transaction {} is not in the mempool of {}", txid, node)),
+        other => Err(anyhow!("Unexpected reply from node: {:?}", other)),
+    }
+}
+
+/// Asks the node at `node` for the fee rate, per 1000 bytes, that gets
+/// a transaction confirmed within `target` blocks. `None` until the node
+/// has seen enough blocks.
+pub fn fetch_fee_estimate(node: &str, target: u32) -> Result<Option<i64>> {
+    let mut stream = TcpStream::connect(node)
+        .map_err(|e| anyhow!("Could not reach node at {}: {}", node, e))?;
+    send_message(&mut stream, &Message::EstimateFee(target))?;
+    match read_message(&mut stream)? {
+        Message::FeeEstimate(fee_rate) => Ok(fee_rate),
+        other => Err(anyhow!("Unexpected reply from node: {:?}", other)),
+    }
+}
+
+/// Asks the node at `node` for the size and fee rates of its mempool
+pub fn fetch_mempool_info(node: &str) -> Result<MempoolInfo> {
+    let mut stream = TcpStream::connect(node)
+        .map_err(|e| anyhow!("Could not reach node at {}: {}", node, e))?;
+    send_message(&mut stream, &Message::GetMempoolInfo)?;
+    match read_message(&mut stream)? {
+        Message::MempoolInfo(info) => Ok(info),
+        other => Err(anyhow!("Unexpected reply from node: {:?}", other)),
+    }
+}
+
+/// Asks the node at `node` for the coins of `script_pubkey` that are
+/// free to spend. Wallet commands use it instead of the chain, which a
+/// running node keeps locked.
+pub fn fetch_utxos(node: &str, script_pubkey: &Script) -> Result<Vec<Coin>> {
+    let mut stream = TcpStream::connect(node)
+        .map_err(|e| anyhow!("Could not reach node at {}: {}", node, e))?;
+    send_message(&mut stream, &Message::GetUtxos(script_pubkey.clone()))?;
+    match read_message(&mut stream)? {
+        Message::Utxos(coins) => Ok(coins),
+        other => Err(anyhow!("Unexpected reply from node: {:?}", other)),
+    }
+}
+
+/// Asks the node at `node` for the unspent outputs at `outpoints`
+pub fn fetch_tx_outs(node: &str, outpoints: Vec<(String, i32)>) -> Result<Vec<Option<TXOutput>>> {
+    let mut stream = TcpStream::connect(node)
+        .map_err(|e| anyhow!("Could not reach node at {}: {}", node, e))?;
+    send_message(&mut stream, &Message::GetTxOuts(outpoints))?;
+    match read_message(&mut stream)? {
+        Message::TxOuts(outputs) => Ok(outputs),
+        other => Err(anyhow!("Unexpected reply from node: {:?}", other)),
+    }
+}
+
+/// State shared by the connections of a running node
+#[derive(Clone)]
+struct Node {
+    utxo_set: UTXOSet,
+    mempool: Arc<Mutex<Mempool>>,
+    /// Transactions spending outputs the node has not seen. Locked after
+    /// the mempool.
+    orphan_txs: Arc<Mutex<OrphanPool<Transaction>>>,
+    /// Blocks whose parent the node has not seen. Locked after the
+    /// orphan transactions.
+    orphan_blocks: Arc<Mutex<OrphanPool<Block>>>,
+    miner_addr: Option<String>,
+    /// Nodes that new transactions and blocks are relayed to
+    peers: Vec<String>,
+    /// Where the mempool is saved across restarts
+    mempool_file: PathBuf,
+}
+
+pub fn start_miner_node(port: u16, miner_addr: &str, utxo_set: UTXOSet, mempool: Mempool, peers: Vec<String>, data_dir: &Path) -> Result<()> {
+    start_node(port, utxo_set, mempool, Some(miner_addr.to_string()), peers, data_dir)
+}
+
+pub fn start_full_node(port: u16, utxo_set: UTXOSet, mempool: Mempool, peers: Vec<String>, data_dir: &Path) -> Result<()> {
+    start_node(port, utxo_set, mempool, None, peers, data_dir)
+}
+
+fn start_node(port: u16, utxo_set: UTXOSet, mut mempool: Mempool, miner_addr: Option<String>, peers: Vec<String>, data_dir: &Path) -> Result<()> {
     let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
-    
+    let mempool_file = data_dir.join(MEMPOOL_FILE);
+    if let Err(e) = mempool.load(&utxo_set.blockchain, &mempool_file) {
+        warn!("Could not load the saved mempool: {}", e);
+    }
+    let node = Node {
+        utxo_set,
+        mempool: Arc::new(Mutex::new(mempool)),
+        orphan_txs: Arc::new(Mutex::new(OrphanPool::new(MAX_ORPHAN_TXS))),
+        orphan_blocks: Arc::new(Mutex::new(OrphanPool::new(MAX_ORPHAN_BLOCKS))),
+        miner_addr,
+        peers,
+        mempool_file,
+    };
+
+    let saver = node.clone();
+    thread::spawn(move || loop {
+        thread::sleep(MEMPOOL_SAVE_INTERVAL);
+        saver.save_mempool();
+    });
+    let saver = node.clone();
+    ctrlc::set_handler(move || {
+        saver.save_mempool();
+        std::process::exit(0);
+    })?;
+
     for stream in listener.incoming() {
         let stream = stream?;
-        let utxo_set = utxo_set.clone();
-        
+        let node = node.clone();
+
         thread::spawn(move || {
-            handle_connection(stream, &utxo_set, None);
+            if let Err(e) = handle_connection(stream, &node) {
+                warn!("Connection failed: {}", e);
+            }
         });
     }
-    
+
     Ok(())
 }
 
-fn handle_connection(stream: TcpStream, _utxo_set: &UTXOSet, _miner_addr: Option<String>) {
+fn handle_connection(mut stream: TcpStream, node: &Node) -> Result<()> {
     info!("New connection from {:?}", stream.peer_addr());
-}
\ No newline at end of file
+    stream.set_read_timeout(Some(PEER_TIMEOUT))?;
+
+    let reply = match read_message(&mut stream)? {
+        Message::Tx(tx) => node.receive_transaction(tx, &mut stream),
+        Message::Block(block) => node.receive_block(block, &mut stream),
+        request @ (Message::GetMempoolTx(_) | Message::GetBlock(_)) => node.serve(&request),
+        Message::GetMempoolInfo => match node.mempool.lock().unwrap().info() {
+            Ok(info) => Message::MempoolInfo(info),
+            Err(e) => Message::Rejected(e.to_string()),
+        },
+        Message::EstimateFee(target) => {
+            let mempool = node.mempool.lock().unwrap();
+            match mempool.estimate_fee(&node.utxo_set.blockchain, target as usize) {
+                Ok(fee_rate) => Message::FeeEstimate(fee_rate),
+                Err(e) => Message::Rejected(e.to_string()),
+            }
+        }
+        Message::GetUtxos(script_pubkey) => {
+            let mempool = node.mempool.lock().unwrap();
+            match node.utxo_set.find_coins(&script_pubkey) {
+                Ok(coins) => Message::Utxos(coins.into_iter()
+                    .filter(|coin| !mempool.spends(&coin.txid, coin.vout))
+                    .collect()),
+                Err(e) => Message::Rejected(e.to_string()),
+            }
+        }
+        Message::GetTxOuts(outpoints) => {
+            let outputs: Result<Vec<_>> = outpoints.iter()
+                .map(|(txid, vout)| node.utxo_set.find_output(txid, *vout))
+                .collect();
+            match outputs {
+                Ok(outputs) => Message::TxOuts(outputs),
+                Err(e) => Message::Rejected(e.to_string()),
+            }
+        }
+        other => Message::Rejected(format!("Unexpected message {:?}", other)),
+    };
+    send_message(&mut stream, &reply)
+}
+
+/// Checks what can be checked of a block without its parent, so that
+/// holding it as an orphan costs its sender the work of mining it
+fn check_orphan_block(block: &Block) -> Result<()> {
+    block.check_proof_of_work()?;
+    block.check_merkle_root()?;
+    if block.difficulty != TARGET_BITS {
+        return Err(anyhow!("Unexpected difficulty {} (expected {})", block.difficulty, TARGET_BITS));
+    }
+    Ok(())
+}
+
+/// Asks the peer at the other end of `stream` for a missing parent
+fn request_parent(stream: &mut TcpStream, request: Message) -> Result<Message> {
+    send_message(stream, &request)?;
+    read_message(stream)
+}
+
+impl Node {
+    /// Writes the mempool to its file. Holding the lock also waits for a
+    /// block being mined to be stored.
+    fn save_mempool(&self) {
+        let mempool = self.mempool.lock().unwrap();
+        match mempool.save(&self.mempool_file) {
+            Ok(()) => info!("Saved {} mempool transactions", mempool.len()),
+            Err(e) => warn!("Could not save the mempool: {}", e),
+        }
+    }
+
+    /// Answers a request for a mempool transaction or a block
+    fn serve(&self, request: &Message) -> Message {
+        match request {
+            Message::GetMempoolTx(txid) => {
+                let mempool = self.mempool.lock().unwrap();
+                Message::MempoolTx(mempool.get(txid).map(|entry| entry.tx.clone()))
+            }
+            Message::GetBlock(hash) => Message::BlockData(self.utxo_set.blockchain.get_block(hash).ok()),
+            other => Message::Rejected(format!("Unexpected request {:?}", other)),
+        }
+    }
+
+    /// Adds `tx` to the mempool. If it spends outputs of transactions the
+    /// node has not seen, it is held as an orphan and the missing parents
+    /// are requested from the peer on `stream`. The locks are released
+    /// while waiting for the peer, and the parents are validated once
+    /// they arrive.
+    fn receive_transaction(&self, tx: Transaction, stream: &mut TcpStream) -> Message {
+        let txid = tx.id.clone();
+        let bc = &self.utxo_set.blockchain;
+        let mut queue = vec![tx];
+        let mut accepted = Vec::new();
+        let mut error = None;
+        let mut requests = 0;
+        while !queue.is_empty() {
+            let mut wanted = Vec::new();
+            let mut mempool = self.mempool.lock().unwrap();
+            let mut orphans = self.orphan_txs.lock().unwrap();
+            while let Some(tx) = queue.pop() {
+                let id = tx.id.clone();
+                let result = self.missing_inputs(&mempool, &tx).and_then(|missing| {
+                    if missing.is_empty() {
+                        mempool.add(bc, tx.clone())?;
+                        accepted.push(tx);
+                        queue.extend(orphans.take_children(&id));
+                        return Ok(());
+                    }
+                    let size = tx.size()?;
+                    if size > MAX_ORPHAN_TX_SIZE {
+                        return Err(anyhow!("Orphan transaction {} of {} bytes is too large to hold", id, size));
+                    }
+                    info!("Holding orphan transaction {} until {} arrive", id, missing.join(", "));
+                    for parent in &missing {
+                        if requests < MAX_ORPHAN_TXS && !orphans.contains(parent) && !wanted.contains(parent) {
+                            requests += 1;
+                            wanted.push(parent.clone());
+                        }
+                    }
+                    orphans.add(id.clone(), missing, tx);
+                    Ok(())
+                });
+                if let Err(e) = result {
+                    if id == txid {
+                        error = Some(e);
+                    } else {
+                        info!("Dropping transaction {}: {}", id, e);
+                    }
+                }
+            }
+            drop(orphans);
+            drop(mempool);
+
+            for parent in wanted {
+                match request_parent(stream, Message::GetMempoolTx(parent.clone())) {
+                    Ok(Message::MempoolTx(Some(tx))) => queue.push(tx),
+                    Ok(_) => {}
+                    Err(e) => {
+                        info!("Peer did not send transaction {}: {}", parent, e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut mempool = self.mempool.lock().unwrap();
+        let orphans = self.orphan_txs.lock().unwrap();
+        let reply = if mempool.get(&txid).is_some() {
+            Message::TxAccepted(txid)
+        } else if let Some(missing) = orphans.missing(&txid) {
+            Message::Orphaned(missing.to_vec())
+        } else {
+            Message::Rejected(error.map(|e| e.to_string()).unwrap_or_else(|| format!("Transaction {} was not accepted", txid)))
+        };
+        drop(orphans);
+
+        let mut blocks = Vec::new();
+        if self.miner_addr.is_some() && !accepted.is_empty() {
+            match self.mine(&mut mempool) {
+                Ok(block) => blocks.extend(block),
+                Err(e) => warn!("Mining failed: {}", e),
+            }
+        }
+        drop(mempool);
+        self.relay(accepted, blocks);
+        reply
+    }
+
+    /// Ids of the transactions whose outputs `tx` spends that are neither
+    /// in the mempool nor have unspent outputs on the chain
+    fn missing_inputs(&self, mempool: &Mempool, tx: &Transaction) -> Result<Vec<String>> {
+        let mut missing = Vec::new();
+        for input in &tx.vin {
+            if mempool.get(&input.txid).is_none()
+                && self.utxo_set.blockchain.get_utxos(&input.txid)?.is_none()
+                && !missing.contains(&input.txid)
+            {
+                missing.push(input.txid.clone());
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Connects `block` to the chain, or stores it on a side branch and
+    /// switches to that branch once it is longer. If its parent is
+    /// unknown, it is held as an orphan and the missing blocks are
+    /// requested from the peer on `stream`, back to one the node has. As
+    /// for transactions, nothing is locked while waiting for the peer.
+    fn receive_block(&self, block: Block, stream: &mut TcpStream) -> Message {
+        let hash = block.hash.clone();
+        let bc = &self.utxo_set.blockchain;
+        let mut queue = vec![block];
+        let mut stored = Vec::new();
+        let mut accepted = Vec::new();
+        let mut error = None;
+        let mut requests = 0;
+        while !queue.is_empty() {
+            let mut wanted = Vec::new();
+            let mut mempool = self.mempool.lock().unwrap();
+            let mut orphan_txs = self.orphan_txs.lock().unwrap();
+            let mut orphans = self.orphan_blocks.lock().unwrap();
+            while let Some(block) = queue.pop() {
+                let id = block.hash.clone();
+                if bc.get_block(&id).is_ok() {
+                    continue;
+                }
+                let result = if bc.get_block(&block.prev_block_hash).is_ok() {
+                    bc.process_block(&block).and_then(|(disconnected, connected)| {
+                        for block in &connected {
+                            info!("Connected block {} at height {}", block.hash, block.height);
+                            accepted.extend(self.block_connected(&mut mempool, &mut orphan_txs, block));
+                        }
+                        queue.extend(orphans.take_children(&id));
+                        stored.push(block);
+                        if disconnected.is_empty() {
+                            return Ok(());
+                        }
+                        mempool.readd_disconnected(bc, &disconnected)
+                    })
+                } else {
+                    check_orphan_block(&block).map(|()| {
+                        let parent = block.prev_block_hash.clone();
+                        info!("Holding orphan block {} until {} arrives", id, parent);
+                        orphans.add(id.clone(), vec![parent.clone()], block);
+                        if requests < MAX_ORPHAN_BLOCKS && !orphans.contains(&parent) && !wanted.contains(&parent) {
+                            requests += 1;
+                            wanted.push(parent);
+                        }
+                    })
+                };
+                if let Err(e) = result {
+                    if id == hash {
+                        error = Some(e);
+                    } else {
+                        info!("Dropping block {}: {}", id, e);
+                    }
+                }
+            }
+            drop(orphans);
+            drop(orphan_txs);
+            drop(mempool);
+
+            for parent in wanted {
+                match request_parent(stream, Message::GetBlock(parent.clone())) {
+                    Ok(Message::BlockData(Some(block))) => queue.push(block),
+                    Ok(_) => {}
+                    Err(e) => {
+                        info!("Peer did not send block {}: {}", parent, e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        let orphans = self.orphan_blocks.lock().unwrap();
+        let reply = if stored.iter().any(|block| block.hash == hash) {
+            Message::BlockAccepted(hash)
+        } else if let Some(missing) = orphans.missing(&hash) {
+            Message::Orphaned(missing.to_vec())
+        } else {
+            Message::Rejected(error.map(|e| e.to_string()).unwrap_or_else(|| format!("Block {} is already known", hash)))
+        };
+        drop(orphans);
+        self.relay(accepted, stored);
+        reply
+    }
+
+    /// Updates the mempool for a block just connected and adds the orphan
+    /// transactions that spend its outputs. Returns those accepted.
+    fn block_connected(&self, mempool: &mut Mempool, orphans: &mut OrphanPool<Transaction>, block: &Block) -> Vec<Transaction> {
+        mempool.remove_for_block(block);
+        let mut queue: Vec<Transaction> = block.transactions.iter()
+            .flat_map(|tx| orphans.take_children(&tx.id))
+            .collect();
+        let mut accepted = Vec::new();
+        while let Some(tx) = queue.pop() {
+            let id = tx.id.clone();
+            let result = self.missing_inputs(mempool, &tx).and_then(|missing| {
+                if missing.is_empty() {
+                    mempool.add(&self.utxo_set.blockchain, tx.clone())?;
+                    queue.extend(orphans.take_children(&id));
+                    accepted.push(tx);
+                } else {
+                    orphans.add(id.clone(), missing, tx);
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                info!("Dropping orphan transaction {}: {}", id, e);
+            }
+        }
+        accepted
+    }
+
+    /// Passes new transactions and blocks on to the peers in the
+    /// background, answering their requests for parents
+    fn relay(&self, txs: Vec<Transaction>, blocks: Vec<Block>) {
+        if self.peers.is_empty() || (txs.is_empty() && blocks.is_empty()) {
+            return;
+        }
+        let node = self.clone();
+        thread::spawn(move || {
+            let messages: Vec<Message> = txs.into_iter().map(Message::Tx)
+                .chain(blocks.into_iter().map(Message::Block))
+                .collect();
+            for peer in &node.peers {
+                for message in &messages {
+                    match submit(peer, message, &|request| node.serve(request)) {
+                        Ok(Message::Rejected(reason)) => info!("Peer {} rejected relayed item: {}", peer, reason),
+                        Ok(_) => {}
+                        Err(e) => warn!("Could not relay to {}: {}", peer, e),
+                    }
+                }
+            }
+        });
+    }
+
+    /// Mines a block with the best-paying mempool transactions, paying the
+    /// subsidy and fees to the miner address
+    fn mine(&self, mempool: &mut Mempool) -> Result<Option<Block>> {
+        let miner_addr = match &self.miner_addr {
+            Some(addr) => addr.clone(),
+            None => return Ok(None),
+        };
+
+        let template = mempool.block_template(BLOCK_TEMPLATE_SIZE);
+        let fees = sum_values(template.iter().map(|entry| entry.fee))?;
+        let mut txs = vec![Transaction::new_coinbase(miner_addr, String::new(), fees)?];
+        txs.extend(template.iter().map(|entry| entry.tx.clone()));
+
+        let mut bc = self.utxo_set.blockchain.clone();
+        bc.mine_block(txs)?;
+        let block = bc.get_block_by_height(bc.get_best_height()?)?;
+        mempool.remove_for_block(&block);
+        info!("Mined block {} at height {}", block.hash, block.height);
+        Ok(Some(block))
+    }
+}
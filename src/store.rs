@@ -0,0 +1,365 @@
+use crate::block::Block;
+use crate::transaction::{TXOutput, TXOutputs};
+use anyhow::{anyhow, Result};
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use sled::transaction::ConflictableTransactionResult;
+use sled::{Batch, Db, Transactional, Tree};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::RwLock;
+
+const HEIGHT_TREE: &str = "heights";
+const TX_INDEX_TREE: &str = "txindex";
+const UTXO_TREE: &str = "utxoset";
+const UNDO_TREE: &str = "undo";
+const TIP_KEY: &str = "l";
+/// Key of the block hash the UTXO set was last written for
+const UTXO_TIP_KEY: &str = "u";
+const TX_INDEX_FLAG: &str = "txindex";
+
+/// Position of a transaction on the active chain
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxLocation {
+    pub block_hash: String,
+    pub position: u32,
+}
+
+/// An output removed from the UTXO set by a block, kept so the block
+/// can be disconnected again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpentOutput {
+    pub txid: String,
+    pub vout: i32,
+    pub output: TXOutput,
+    /// Height the output was created at
+    pub height: i32,
+}
+
+/// A set of writes that a `ChainStore` must apply atomically.
+/// `None` values delete the entry. Clears run before the other writes.
+#[derive(Default)]
+pub struct ChainBatch {
+    pub blocks: Vec<Block>,
+    pub tip: Option<String>,
+    pub utxo_tip: Option<String>,
+    pub heights: Vec<(i32, Option<String>)>,
+    pub tx_index: Vec<(String, Option<TxLocation>)>,
+    pub utxos: Vec<(String, Option<TXOutputs>)>,
+    pub undo: Vec<(String, Option<Vec<SpentOutput>>)>,
+    /// Turns the transaction index on or off
+    pub tx_index_enabled: Option<bool>,
+    pub clear_indexes: bool,
+    pub clear_utxos: bool,
+}
+
+/// Persistent state of a chain: blocks, tip pointer, indexes and UTXO set
+pub trait ChainStore: Send + Sync {
+    fn get_block(&self, hash: &str) -> Result<Option<Block>>;
+    fn get_tip(&self) -> Result<Option<String>>;
+    fn get_utxo_tip(&self) -> Result<Option<String>>;
+    fn get_block_hash(&self, height: i32) -> Result<Option<String>>;
+    fn height_count(&self) -> Result<usize>;
+    fn get_tx_location(&self, txid: &str) -> Result<Option<TxLocation>>;
+    fn get_utxos(&self, txid: &str) -> Result<Option<TXOutputs>>;
+    fn utxo_iter(&self) -> Box<dyn Iterator<Item = Result<(String, TXOutputs)>> + '_>;
+    fn utxo_count(&self) -> Result<usize>;
+    fn get_undo(&self, hash: &str) -> Result<Option<Vec<SpentOutput>>>;
+    fn tx_index_enabled(&self) -> Result<bool>;
+    fn commit(&self, batch: ChainBatch) -> Result<()>;
+}
+
+fn height_key(height: i32) -> [u8; 4] {
+    (height as u32).to_be_bytes()
+}
+
+/// `ChainStore` backed by a sled database, one tree per kind of record
+pub struct SledStore {
+    db: Db,
+    heights: Tree,
+    tx_index: Tree,
+    utxos: Tree,
+    undo: Tree,
+}
+
+impl SledStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let db = sled::open(path)?;
+        Ok(SledStore {
+            heights: db.open_tree(HEIGHT_TREE)?,
+            tx_index: db.open_tree(TX_INDEX_TREE)?,
+            utxos: db.open_tree(UTXO_TREE)?,
+            undo: db.open_tree(UNDO_TREE)?,
+            db,
+        })
+    }
+
+    fn get_string(tree: &Tree, key: &[u8]) -> Result<Option<String>> {
+        match tree.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl ChainStore for SledStore {
+    fn get_block(&self, hash: &str) -> Result<Option<Block>> {
+        match self.db.get(hash)? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_tip(&self) -> Result<Option<String>> {
+        Self::get_string(&self.db, TIP_KEY.as_bytes())
+    }
+
+    fn get_utxo_tip(&self) -> Result<Option<String>> {
+        Self::get_string(&self.db, UTXO_TIP_KEY.as_bytes())
+    }
+
+    fn get_block_hash(&self, height: i32) -> Result<Option<String>> {
+        Self::get_string(&self.heights, &height_key(height))
+    }
+
+    fn height_count(&self) -> Result<usize> {
+        Ok(self.heights.len())
+    }
+
+    fn get_tx_location(&self, txid: &str) -> Result<Option<TxLocation>> {
+        match self.tx_index.get(txid)? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_utxos(&self, txid: &str) -> Result<Option<TXOutputs>> {
+        match self.utxos.get(txid)? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn utxo_iter(&self) -> Box<dyn Iterator<Item = Result<(String, TXOutputs)>> + '_> {
+        Box::new(self.utxos.iter().map(|item| {
+            let (key, value) = item?;
+            Ok((String::from_utf8(key.to_vec())?, deserialize(&value)?))
+        }))
+    }
+
+    fn utxo_count(&self) -> Result<usize> {
+        Ok(self.utxos.len())
+    }
+
+    fn get_undo(&self, hash: &str) -> Result<Option<Vec<SpentOutput>>> {
+        match self.undo.get(hash)? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn tx_index_enabled(&self) -> Result<bool> {
+        Ok(self.db.contains_key(TX_INDEX_FLAG)?)
+    }
+
+    fn commit(&self, batch: ChainBatch) -> Result<()> {
+        let mut blocks = Batch::default();
+        let mut heights = Batch::default();
+        let mut tx_index = Batch::default();
+        let mut utxos = Batch::default();
+        let mut undo = Batch::default();
+
+        // sled batches cannot clear a tree, so removals are queued for every
+        // existing key; later inserts of the same key win
+        if batch.clear_indexes {
+            for key in self.heights.iter().keys() {
+                heights.remove(key?);
+            }
+            for key in self.tx_index.iter().keys() {
+                tx_index.remove(key?);
+            }
+        }
+        if batch.clear_utxos {
+            for key in self.utxos.iter().keys() {
+                utxos.remove(key?);
+            }
+        }
+
+        for block in &batch.blocks {
+            blocks.insert(block.hash.as_bytes(), serialize(block)?);
+        }
+        if let Some(tip) = &batch.tip {
+            blocks.insert(TIP_KEY, tip.as_bytes());
+        }
+        if let Some(utxo_tip) = &batch.utxo_tip {
+            blocks.insert(UTXO_TIP_KEY, utxo_tip.as_bytes());
+        }
+        match batch.tx_index_enabled {
+            Some(true) => blocks.insert(TX_INDEX_FLAG, "1"),
+            Some(false) => blocks.remove(TX_INDEX_FLAG),
+            None => {}
+        }
+        for (height, hash) in &batch.heights {
+            match hash {
+                Some(hash) => heights.insert(&height_key(*height), hash.as_bytes()),
+                None => heights.remove(&height_key(*height)),
+            }
+        }
+        for (txid, location) in &batch.tx_index {
+            match location {
+                Some(location) => tx_index.insert(txid.as_bytes(), serialize(location)?),
+                None => tx_index.remove(txid.as_bytes()),
+            }
+        }
+        for (txid, outs) in &batch.utxos {
+            match outs {
+                Some(outs) => utxos.insert(txid.as_bytes(), serialize(outs)?),
+                None => utxos.remove(txid.as_bytes()),
+            }
+        }
+        for (hash, spent) in &batch.undo {
+            match spent {
+                Some(spent) => undo.insert(hash.as_bytes(), serialize(spent)?),
+                None => undo.remove(hash.as_bytes()),
+            }
+        }
+
+        (&*self.db, &self.heights, &self.tx_index, &self.utxos, &self.undo)
+            .transaction(|(db, heights_tree, tx_index_tree, utxo_tree, undo_tree)| -> ConflictableTransactionResult<(), ()> {
+                db.apply_batch(&blocks)?;
+                heights_tree.apply_batch(&heights)?;
+                tx_index_tree.apply_batch(&tx_index)?;
+                utxo_tree.apply_batch(&utxos)?;
+                undo_tree.apply_batch(&undo)?;
+                Ok(())
+            })
+            .map_err(|e| anyhow!("Failed to commit chain update: {:?}", e))?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct MemoryState {
+    blocks: HashMap<String, Block>,
+    tip: Option<String>,
+    utxo_tip: Option<String>,
+    heights: BTreeMap<i32, String>,
+    tx_index: HashMap<String, TxLocation>,
+    utxos: BTreeMap<String, TXOutputs>,
+    undo: HashMap<String, Vec<SpentOutput>>,
+    tx_index_enabled: bool,
+}
+
+/// `ChainStore` kept entirely in memory, for tests and simulations that
+/// need many independent chains in one process
+#[derive(Default)]
+pub struct MemoryStore {
+    state: RwLock<MemoryState>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn get_block(&self, hash: &str) -> Result<Option<Block>> {
+        Ok(self.state.read().unwrap().blocks.get(hash).cloned())
+    }
+
+    fn get_tip(&self) -> Result<Option<String>> {
+        Ok(self.state.read().unwrap().tip.clone())
+    }
+
+    fn get_utxo_tip(&self) -> Result<Option<String>> {
+        Ok(self.state.read().unwrap().utxo_tip.clone())
+    }
+
+    fn get_block_hash(&self, height: i32) -> Result<Option<String>> {
+        Ok(self.state.read().unwrap().heights.get(&height).cloned())
+    }
+
+    fn height_count(&self) -> Result<usize> {
+        Ok(self.state.read().unwrap().heights.len())
+    }
+
+    fn get_tx_location(&self, txid: &str) -> Result<Option<TxLocation>> {
+        Ok(self.state.read().unwrap().tx_index.get(txid).cloned())
+    }
+
+    fn get_utxos(&self, txid: &str) -> Result<Option<TXOutputs>> {
+        Ok(self.state.read().unwrap().utxos.get(txid).cloned())
+    }
+
+    fn utxo_iter(&self) -> Box<dyn Iterator<Item = Result<(String, TXOutputs)>> + '_> {
+        let utxos: Vec<_> = self.state.read().unwrap().utxos.iter()
+            .map(|(txid, outs)| Ok((txid.clone(), outs.clone())))
+            .collect();
+        Box::new(utxos.into_iter())
+    }
+
+    fn utxo_count(&self) -> Result<usize> {
+        Ok(self.state.read().unwrap().utxos.len())
+    }
+
+    fn get_undo(&self, hash: &str) -> Result<Option<Vec<SpentOutput>>> {
+        Ok(self.state.read().unwrap().undo.get(hash).cloned())
+    }
+
+    fn tx_index_enabled(&self) -> Result<bool> {
+        Ok(self.state.read().unwrap().tx_index_enabled)
+    }
+
+    fn commit(&self, batch: ChainBatch) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+
+        if batch.clear_indexes {
+            state.heights.clear();
+            state.tx_index.clear();
+        }
+        if batch.clear_utxos {
+            state.utxos.clear();
+        }
+
+        for block in batch.blocks {
+            state.blocks.insert(block.hash.clone(), block);
+        }
+        if batch.tip.is_some() {
+            state.tip = batch.tip;
+        }
+        if batch.utxo_tip.is_some() {
+            state.utxo_tip = batch.utxo_tip;
+        }
+        if let Some(enabled) = batch.tx_index_enabled {
+            state.tx_index_enabled = enabled;
+        }
+        for (height, hash) in batch.heights {
+            match hash {
+                Some(hash) => state.heights.insert(height, hash),
+                None => state.heights.remove(&height),
+            };
+        }
+        for (txid, location) in batch.tx_index {
+            match location {
+                Some(location) => state.tx_index.insert(txid, location),
+                None => state.tx_index.remove(&txid),
+            };
+        }
+        for (txid, outs) in batch.utxos {
+            match outs {
+                Some(outs) => state.utxos.insert(txid, outs),
+                None => state.utxos.remove(&txid),
+            };
+        }
+        for (hash, spent) in batch.undo {
+            match spent {
+                Some(spent) => state.undo.insert(hash, spent),
+                None => state.undo.remove(&hash),
+            };
+        }
+
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
-use crate::utxoset::UTXOSet;
+use crate::script::{verify_script, Op, Script, ScriptContext};
+use crate::utxoset::Coin;
 use crate::wallets::Wallet;
 use anyhow::{anyhow, Result};
 use bincode::serialize;
@@ -6,55 +7,117 @@ use log::info;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use bs58;
+use std::collections::BTreeMap;
 
-const SUBSIDY: i32 = 10;
+pub const SUBSIDY: i32 = 10;
+/// Largest value an output, or the outputs of a transaction together,
+/// may carry
+pub const MAX_MONEY: i32 = 21_000_000;
+/// Version of new transactions; from 2 on, input sequences can carry
+/// relative lock times, and from 3 on signatures cover the value and
+/// locking script of every spent output
+pub const TX_VERSION: i32 = 3;
+/// Lock times below this are block heights, from it on unix times in
+/// seconds
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// Sequence of an input that opts out of every lock time
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+/// Highest sequence that signals the transaction may be replaced in the
+/// mempool by one paying a higher fee
+pub const SEQUENCE_RBF: u32 = 0xffff_fffd;
+/// Set in a sequence that carries no relative lock time
+pub const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+/// Set in a sequence whose relative lock is a time rather than blocks
+pub const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+/// Bits of a sequence holding the relative lock value
+pub const SEQUENCE_MASK: u32 = 0xffff;
+/// Relative time locks count units of 2^9 = 512 seconds
+pub const SEQUENCE_GRANULARITY: u32 = 9;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
     pub txid: String,
     pub vout: i32,
-    pub signature: Vec<u8>,
-    pub pub_key: Vec<u8>,
+    /// Satisfies the locking script of the spent output. Holds arbitrary
+    /// data in a coinbase.
+    pub script_sig: Script,
+    /// `SEQUENCE_FINAL` unless the input enables the transaction lock time
+    /// or carries a relative lock time (see `relative_lock`)
+    pub sequence: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TXOutput {
     pub value: i32,
-    pub pub_key_hash: Vec<u8>,
+    pub script_pubkey: Script,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Unspent outputs of one transaction, keyed by output index
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+    pub outputs: BTreeMap<i32, TXOutput>,
+    /// Height of the block that created the outputs, for relative locks
+    pub height: i32,
+}
+
+/// A relative lock carried in an input sequence, counted from the block
+/// that created the spent output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelativeLock {
+    Blocks(u32),
+    Seconds(u64),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub id: String,
+    pub version: i32,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>,
+    /// Earliest height, or unix time from `LOCKTIME_THRESHOLD` on, of a
+    /// block that may include the transaction. 0 for none; ignored when
+    /// every input sequence is final.
+    pub lock_time: u32,
 }
 
 impl Transaction {
-    pub fn new_utxo(
-        wallet: &Wallet,
-        to: &str,
-        amount: i32,
-        utxo: &UTXOSet,
-    ) -> Result<Transaction> {
-        info!(
-            "New UTXO Transaction from: {} to: {}",
-            wallet.get_address(),
-            to
-        );
+    /// Builds an unsigned transaction from `from` paying `outputs` and
+    /// `fee` out of `coins`, the unspent outputs of `from`, with any
+    /// change going back to `from` in a single output. Returns it with
+    /// the outputs its inputs spend, which signing needs.
+    pub fn new_unsigned(
+        from: &str,
+        outputs: Vec<TXOutput>,
+        fee: i32,
+        coins: &[Coin],
+    ) -> Result<(Transaction, Vec<TXOutput>)> {
+        info!("New UTXO Transaction from: {} with {} outputs", from, outputs.len());
         
-        let pub_key_hash = wallet.public_key();
-        let pub_key_hash = crate::wallets::hash_pub_key(&pub_key_hash);
+        if fee < 0 || outputs.iter().any(|out| out.value < 0) {
+            return Err(anyhow!("Amounts and fee cannot be negative"));
+        }
+        let amount = sum_values(outputs.iter().map(|out| out.value).chain([fee]))?;
+        let mut vout = outputs;
         
-        let (acc_value, outputs) = utxo.find_spendable_outputs(&pub_key_hash, amount)?;
+        let mut acc_value = 0;
+        let mut vin = Vec::new();
+        let mut prev_outputs = Vec::new();
+        // Even a payment of nothing, such as a lone data output, needs an input
+        for coin in coins {
+            if acc_value >= amount.max(1) {
+                break;
+            }
+            acc_value = sum_values([acc_value, coin.output.value])?;
+            vin.push(TXInput {
+                txid: coin.txid.clone(),
+                vout: coin.vout,
+                script_sig: Script::default(),
+                sequence: SEQUENCE_FINAL,
+            });
+            prev_outputs.push(coin.output.clone());
+        }
         
-        if acc_value < amount {
+        if acc_value < amount || vin.is_empty() {
             return Err(anyhow!(
                 "Insufficient balance: current {}, required {}",
                 acc_value,
@@ -62,30 +125,22 @@ impl Transaction {
             ));
         }
         
-        let mut vin = Vec::new();
-        for (txid, outs) in outputs {
-            for out in outs {
-                let input = TXInput {
-                    txid: txid.clone(),
-                    vout: out,
-                    signature: Vec::new(),
-                    pub_key: wallet.public_key().to_vec(),
-                };
-                vin.push(input);
-            }
-        }
-        
-        let mut vout = vec![TXOutput::new(amount, to)?];
         if acc_value > amount {
-            vout.push(TXOutput::new(acc_value - amount, &wallet.get_address())?);
+            vout.push(TXOutput::new(acc_value - amount, from)?);
         }
         
-        let mut tx = Transaction { id: String::new(), vin, vout };
-        tx.id = tx.hash()?;
-        Ok(tx)
+        let tx = Transaction {
+            id: String::new(),
+            version: TX_VERSION,
+            vin,
+            vout,
+            lock_time: 0,
+        };
+        Ok((tx, prev_outputs))
     }
 
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
+    /// Pays the block subsidy plus `fees` to `to`
+    pub fn new_coinbase(to: String, mut data: String, fees: i32) -> Result<Transaction> {
         info!("New coinbase Transaction to: {}", to);
         
         let mut rand_bytes = [0u8; 32];
@@ -95,18 +150,20 @@ impl Transaction {
             data = format!("Reward to '{}'", to);
         }
         
-        let mut pub_key = data.into_bytes();
-        pub_key.extend_from_slice(&rand_bytes);
+        let mut data = data.into_bytes();
+        data.extend_from_slice(&rand_bytes);
         
         let mut tx = Transaction {
             id: String::new(),
+            version: TX_VERSION,
             vin: vec![TXInput {
                 txid: String::new(),
                 vout: -1,
-                signature: Vec::new(),
-                pub_key,
+                script_sig: Script(vec![Op::Push(data)]),
+                sequence: SEQUENCE_FINAL,
             }],
-            vout: vec![TXOutput::new(SUBSIDY, &to)?],
+            vout: vec![TXOutput::new(sum_values([SUBSIDY, fees])?, &to)?],
+            lock_time: 0,
         };
         
         tx.id = tx.hash()?;
@@ -117,6 +174,108 @@ impl Transaction {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
 
+    /// Delays the transaction until `lock_time`, taking inputs with a
+    /// final sequence out of it so the lock is enforced
+    pub fn set_lock_time(&mut self, lock_time: u32) {
+        self.lock_time = lock_time;
+        for input in &mut self.vin {
+            if input.sequence == SEQUENCE_FINAL {
+                input.sequence = SEQUENCE_FINAL - 1;
+            }
+        }
+    }
+
+    /// Opts in to replace-by-fee on every input that has no lower
+    /// sequence already
+    pub fn signal_rbf(&mut self) {
+        for input in &mut self.vin {
+            input.sequence = input.sequence.min(SEQUENCE_RBF);
+        }
+    }
+
+    /// Whether any input opts in to replace-by-fee
+    pub fn signals_rbf(&self) -> bool {
+        self.vin.iter().any(|input| input.sequence <= SEQUENCE_RBF)
+    }
+
+    /// Serialized size in bytes, which fee rates are measured against
+    pub fn size(&self) -> Result<usize> {
+        Ok(serialize(self)?.len())
+    }
+
+    /// Whether the lock time allows inclusion in a block at `height`
+    /// whose parent has median time past `time` (unix seconds)
+    pub fn is_final(&self, height: i32, time: u64) -> bool {
+        if self.lock_time == 0 || self.vin.iter().all(|input| input.sequence == SEQUENCE_FINAL) {
+            return true;
+        }
+        if self.lock_time < LOCKTIME_THRESHOLD {
+            self.lock_time as i64 <= height as i64
+        } else {
+            self.lock_time as u64 <= time
+        }
+    }
+
+    /// Signature by `wallet` for input `idx`, to be placed in its
+    /// unlocking script
+    pub fn input_signature(&self, idx: usize, wallet: &Wallet, prev_outputs: &[TXOutput]) -> Result<Vec<u8>> {
+        if prev_outputs.len() != self.vin.len() {
+            return Err(anyhow!("Expected {} previous outputs, got {}", self.vin.len(), prev_outputs.len()));
+        }
+        let sighash = self.signature_hash(idx, prev_outputs)?;
+        Ok(wallet.sign(&sighash))
+    }
+
+    /// Runs the unlocking script of every input against the locking
+    /// script of the output it spends. Lock times are checked against the
+    /// transaction's own fields; whether the chain has reached them is
+    /// up to block validation.
+    pub fn verify(&self, prev_outputs: &[TXOutput]) -> Result<()> {
+        if self.is_coinbase() {
+            return Ok(());
+        }
+        if prev_outputs.len() != self.vin.len() {
+            return Err(anyhow!("Expected {} previous outputs, got {}", self.vin.len(), prev_outputs.len()));
+        }
+        
+        for (idx, input) in self.vin.iter().enumerate() {
+            let sighash = self.signature_hash(idx, prev_outputs)?;
+            let ctx = ScriptContext {
+                sighash: &sighash,
+                version: self.version,
+                lock_time: self.lock_time,
+                sequence: input.sequence,
+            };
+            verify_script(&input.script_sig, &prev_outputs[idx].script_pubkey, &ctx)
+                .map_err(|e| anyhow!(
+                    "Input {} of {} cannot spend output {}:{}: {}",
+                    idx, self.id, input.txid, input.vout, e
+                ))?;
+        }
+        
+        Ok(())
+    }
+
+    /// Hash signed by input `idx`: the transaction without id and
+    /// unlocking scripts, with that input's unlocking script replaced by
+    /// the locking script of the output it spends. From version 3 on it
+    /// is followed by all spent outputs, so an offline signer cannot be
+    /// misled about the amounts, and therefore the fee, it signs for.
+    fn signature_hash(&self, idx: usize, prev_outputs: &[TXOutput]) -> Result<Vec<u8>> {
+        let mut copy = self.clone();
+        copy.id.clear();
+        for input in &mut copy.vin {
+            input.script_sig = Script::default();
+        }
+        copy.vin[idx].script_sig = prev_outputs[idx].script_pubkey.clone();
+        
+        let mut serialized = serialize(&copy)?;
+        if self.version >= 3 {
+            serialized.extend(serialize(prev_outputs)?);
+        }
+        Ok(Sha256::digest(&serialized).to_vec())
+    }
+
     pub fn hash(&self) -> Result<String> {
         let mut copy = self.clone();
         copy.id.clear();
@@ -127,43 +286,50 @@ impl Transaction {
     }
 }
 
+/// Adds up amounts, failing when the total goes over `MAX_MONEY`
+pub fn sum_values(values: impl IntoIterator<Item = i32>) -> Result<i32> {
+    values.into_iter().try_fold(0i32, |total, value| {
+        total.checked_add(value)
+            .filter(|total| *total <= MAX_MONEY)
+            .ok_or_else(|| anyhow!("Amount too large: total is over {}", MAX_MONEY))
+    })
+}
+
+impl TXInput {
+    /// Relative lock of the input, which only version 2 transactions
+    /// enforce
+    pub fn relative_lock(&self) -> Option<RelativeLock> {
+        if self.sequence & SEQUENCE_DISABLE_FLAG != 0 {
+            return None;
+        }
+        let value = self.sequence & SEQUENCE_MASK;
+        if self.sequence & SEQUENCE_TYPE_FLAG != 0 {
+            Some(RelativeLock::Seconds((value as u64) << SEQUENCE_GRANULARITY))
+        } else {
+            Some(RelativeLock::Blocks(value))
+        }
+    }
+}
+
 impl TXOutput {
     pub fn new(value: i32, address: &str) -> Result<Self> {
-        let mut txo = TXOutput {
+        Ok(TXOutput {
             value,
-            pub_key_hash: Vec::new(),
-        };
-        txo.lock(address)?;
-        Ok(txo)
+            script_pubkey: Script::from_address(address)?,
+        })
     }
 
-
-    pub fn lock(&mut self, address: &str) -> Result<()> {
-        let decoded = bs58::decode(address)
-            .with_alphabet(bs58::Alphabet::BITCOIN)
-            .into_vec()?;
-        
-        if decoded.len() < 5 {
-            return Err(anyhow!("Invalid address length"));
-        }
-        
-        let version = decoded[0];
-        let pub_key_hash = &decoded[1..decoded.len()-4];
-        let checksum = &decoded[decoded.len()-4..];
-        
-        let mut payload = vec![version];
-        payload.extend(pub_key_hash);
-        let hash = crate::wallets::double_sha256(&payload);
-        
-        if &hash[0..4] != checksum {
-            return Err(anyhow!("Invalid address checksum"));
-        }
-        
-        self.pub_key_hash = pub_key_hash.to_vec();
-        Ok(())
+    /// Zero-value output carrying `data`
+    pub fn new_data(data: &[u8]) -> Result<Self> {
+        Ok(TXOutput {
+            value: 0,
+            script_pubkey: Script::data(data)?,
+        })
     }
 
-        pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
-        self.pub_key_hash == pub_key_hash
+    /// Address paid to, or the locking script for non-standard outputs
+    pub fn address(&self) -> String {
+        self.script_pubkey.address()
+            .unwrap_or_else(|| self.script_pubkey.to_string())
     }
-}
\ No newline at end of file
+}
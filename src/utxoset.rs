@@ -1,12 +1,16 @@
 use crate::blockchain::Blockchain;
-use crate::transaction::TXOutputs;
+use crate::script::Script;
+use crate::transaction::TXOutput;
 use anyhow::Result;
-use bincode::deserialize;
-use sled;
-use std::collections::HashMap;
-use bincode;
+use serde::{Deserialize, Serialize};
 
-const UTXO_BUCKET: &str = "utxoset";
+/// An unspent output and where to find it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Coin {
+    pub txid: String,
+    pub vout: i32,
+    pub output: TXOutput,
+}
 
 #[derive(Clone)]
 pub struct UTXOSet {
@@ -15,63 +19,38 @@ pub struct UTXOSet {
 
 impl UTXOSet {
     pub fn reindex(&self) -> Result<usize> {
-        std::fs::create_dir_all("data")?;
-        let db = sled::open("data/utxoset")?;
-        db.clear()?;
-        
-        let utxos = self.blockchain.find_utxo()?;
-        let len = utxos.len();
-        for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), bincode::serialize(&outs)?)?;
-        }
-        
-        Ok(len)
+        self.blockchain.rebuild_utxo_set()
     }
     
-    pub fn find_spendable_outputs(
-        &self,
-        pub_key_hash: &Vec<u8>,
-        amount: i32,
-    ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
-        let db = sled::open("data/utxoset")?;
-        let mut acc = 0;
-        let mut outputs = HashMap::new();
-        
-        for item in db.iter() {
-            let (key, value) = item?;
-            let txid = String::from_utf8(key.to_vec())?;
-            let outs: TXOutputs = deserialize(&value)?;
-            
-            for (idx, out) in outs.outputs.iter().enumerate() {
-                if acc < amount && out.is_locked_with_key(pub_key_hash) {
-                    acc += out.value;
-                    outputs.entry(txid.clone())
-                        .or_insert_with(Vec::new)
-                        .push(idx as i32);
+    /// Unspent outputs locked by `script_pubkey`
+    pub fn find_coins(&self, script_pubkey: &Script) -> Result<Vec<Coin>> {
+        let mut coins = Vec::new();
+        for item in self.blockchain.utxo_iter() {
+            let (txid, outs) = item?;
+            for (vout, output) in outs.outputs {
+                if output.script_pubkey == *script_pubkey {
+                    coins.push(Coin { txid: txid.clone(), vout, output });
                 }
             }
         }
-        
-        Ok((acc, outputs))
+        Ok(coins)
+    }
+
+    /// Output `vout` of `txid` if it is unspent
+    pub fn find_output(&self, txid: &str, vout: i32) -> Result<Option<TXOutput>> {
+        Ok(self.blockchain.get_utxos(txid)?.and_then(|mut outs| outs.outputs.remove(&vout)))
     }
     
     pub fn get_balance(&self, address: &str) -> Result<i32> {
-        let pub_key_hash = bs58::decode(address)
-            .with_alphabet(bs58::Alphabet::BITCOIN)
-            .into_vec()?;
-        
-        // 跳过版本字节和校验和
-        let pub_key_hash = &pub_key_hash[1..pub_key_hash.len()-4];
+        let script_pubkey = Script::from_address(address)?;
         
         let mut balance = 0;
-        let db = sled::open("data/utxoset")?;
         
-        for item in db.iter() {
-            let (_, value) = item?;
-            let outs: TXOutputs = deserialize(&value)?;
+        for item in self.blockchain.utxo_iter() {
+            let (_, outs) = item?;
             
-            for out in outs.outputs {
-                if out.is_locked_with_key(pub_key_hash) {
+            for out in outs.outputs.values() {
+                if out.script_pubkey == script_pubkey {
                     balance += out.value;
                 }
             }
@@ -81,7 +60,6 @@ impl UTXOSet {
     }
     
     pub fn count_transactions(&self) -> Result<usize> {
-        let db = sled::open("data/utxoset")?;
-        Ok(db.len())
+        self.blockchain.utxo_count()
     }
-}
\ No newline at end of file
+}
@@ -1,34 +1,74 @@
+use argon2::Argon2;
 use bincode::{deserialize, serialize};
-use ring::signature::Ed25519KeyPair;
-use ring::signature::KeyPair;
+use bip39::Mnemonic;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use crate::script::{MultisigPolicy, Script};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use ripemd::Ripemd160;
 use sha2::{Digest, Sha256};
-use std::path::Path;
-use anyhow::Result;
+use std::fs::{OpenOptions, Permissions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
 
-const ADDRESS_VERSION: u8 = 0x00;
-const WALLET_FILE: &str = "data/wallets";
+pub const ADDRESS_VERSION: u8 = 0x00;
+/// Version byte of pay-to-script-hash addresses, such as multisig ones
+pub const MULTISIG_ADDRESS_VERSION: u8 = 0x05;
+/// Network byte of exported private keys
+const PRIVKEY_VERSION: u8 = 0x80;
+/// Wallet files below the data directory
+const WALLET_FILE: &str = "wallets";
+/// Named wallets live here as `<name>.wallet`; the default wallet keeps
+/// its original location in `WALLET_FILE`
+const WALLETS_DIR: &str = "named_wallets";
+const SELECTED_WALLET_FILE: &str = "selected_wallet";
+pub const DEFAULT_WALLET: &str = "default";
+
+/// Length of the PKCS#8 v2 prefix that ring writes before the Ed25519 seed
+const PKCS8_SEED_OFFSET: usize = 16;
+const SEED_LEN: usize = 32;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
-    pkcs8: Vec<u8>,
+    /// Ed25519 private key seed. Wallet files written before HD support
+    /// hold a PKCS#8 document here instead; see `upgrade_legacy_key`.
+    secret: Vec<u8>,
 }
 
 impl Wallet {
-    pub fn new() -> Self {
-        let rng = ring::rand::SystemRandom::new();
-        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
-            .expect("Failed to generate key pair")
-            .as_ref()
+    pub fn from_seed(seed: [u8; SEED_LEN]) -> Self {
+        Wallet { secret: seed.to_vec() }
+    }
+
+    /// Replaces a PKCS#8 document from an old wallet file by its seed
+    fn upgrade_legacy_key(&mut self) -> Result<()> {
+        if self.secret.len() == SEED_LEN {
+            return Ok(());
+        }
+        
+        let key_pair = Ed25519KeyPair::from_pkcs8(&self.secret)
+            .map_err(|_| anyhow!("Invalid key in wallet file"))?;
+        let seed = self.secret.get(PKCS8_SEED_OFFSET..PKCS8_SEED_OFFSET + SEED_LEN)
+            .ok_or_else(|| anyhow!("Invalid key in wallet file"))?
             .to_vec();
+        let upgraded = Ed25519KeyPair::from_seed_unchecked(&seed)
+            .map_err(|_| anyhow!("Invalid key in wallet file"))?;
+        if upgraded.public_key().as_ref() != key_pair.public_key().as_ref() {
+            return Err(anyhow!("Unexpected key layout in wallet file"));
+        }
         
-        Wallet { pkcs8 }
+        self.secret = seed;
+        Ok(())
     }
 
     fn key_pair(&self) -> Ed25519KeyPair {
-        Ed25519KeyPair::from_pkcs8(&self.pkcs8)
+        Ed25519KeyPair::from_seed_unchecked(&self.secret)
             .expect("Invalid key pair")
     }
 
@@ -40,20 +80,94 @@ impl Wallet {
         self.key_pair().sign(message).as_ref().to_vec()
     }
 
+    /// Encodes the private key for `dump-privkey`: base58check of
+    /// `PRIVKEY_VERSION` followed by the seed
+    pub fn to_wif(&self) -> String {
+        base58check_encode(PRIVKEY_VERSION, &self.secret)
+    }
+
+    pub fn from_wif(wif: &str) -> Result<Self> {
+        let (version, payload) = base58check_decode(wif.trim())
+            .map_err(|e| anyhow!("Invalid private key: {}", e))?;
+        if version != PRIVKEY_VERSION {
+            return Err(anyhow!("Invalid private key: unexpected version byte {:#04x}", version));
+        }
+        let seed: [u8; SEED_LEN] = payload.try_into()
+            .map_err(|_| anyhow!("Invalid private key: wrong length"))?;
+        Ok(Wallet::from_seed(seed))
+    }
+
+    /// Signs `message` for `verify_message`. The result is base58 of the
+    /// public key followed by the signature, since an address only
+    /// commits to the key's hash.
+    pub fn sign_message(&self, message: &str) -> String {
+        let mut data = self.public_key();
+        data.extend(self.sign(&message_hash(message)));
+        bs58::encode(data)
+            .with_alphabet(bs58::Alphabet::BITCOIN)
+            .into_string()
+    }
+
     pub fn get_address(&self) -> String {
         let pubkey = self.public_key();
         let pubkey_hash = hash_pub_key(&pubkey);
-        
-        let mut payload = vec![ADDRESS_VERSION];
-        payload.extend_from_slice(&pubkey_hash);
-        
-        let checksum = double_sha256(&payload);
-        payload.extend_from_slice(&checksum[0..4]);
-        
-        bs58::encode(payload)
-            .with_alphabet(bs58::Alphabet::BITCOIN)
-            .into_string()
+        encode_address(&pubkey_hash)
+    }
+}
+
+pub fn encode_address(pub_key_hash: &[u8]) -> String {
+    base58check_encode(ADDRESS_VERSION, pub_key_hash)
+}
+
+/// Returns the public key hash of a checksummed base58 single-key address
+pub fn decode_address(address: &str) -> Result<Vec<u8>> {
+    match decode_versioned_address(address)? {
+        (ADDRESS_VERSION, hash) => Ok(hash),
+        _ => Err(anyhow!("{} is not a single-key address", address)),
+    }
+}
+
+pub fn encode_versioned_address(version: u8, hash: &[u8]) -> String {
+    base58check_encode(version, hash)
+}
+
+/// Returns the version byte and hash of a checksummed base58 address
+pub fn decode_versioned_address(address: &str) -> Result<(u8, Vec<u8>)> {
+    base58check_decode(address)
+        .map_err(|e| anyhow!("Invalid address: {}", e))
+}
+
+/// Base58 of a version byte, the payload and the first 4 bytes of its
+/// double SHA-256
+fn base58check_encode(version: u8, data: &[u8]) -> String {
+    let mut payload = vec![version];
+    payload.extend_from_slice(data);
+    
+    let checksum = double_sha256(&payload);
+    payload.extend_from_slice(&checksum[0..4]);
+    
+    bs58::encode(payload)
+        .with_alphabet(bs58::Alphabet::BITCOIN)
+        .into_string()
+}
+
+/// Returns the version byte and payload of a base58check string
+fn base58check_decode(encoded: &str) -> Result<(u8, Vec<u8>)> {
+    let decoded = bs58::decode(encoded)
+        .with_alphabet(bs58::Alphabet::BITCOIN)
+        .into_vec()?;
+    
+    if decoded.len() < 5 {
+        return Err(anyhow!("too short"));
+    }
+    
+    let (payload, checksum) = decoded.split_at(decoded.len() - 4);
+    let hash = double_sha256(payload);
+    if &hash[0..4] != checksum {
+        return Err(anyhow!("bad checksum"));
     }
+    
+    Ok((payload[0], payload[1..].to_vec()))
 }
 
 pub fn hash_pub_key(pubkey: &[u8]) -> Vec<u8> {
@@ -65,53 +179,885 @@ pub fn hash_pub_key(pubkey: &[u8]) -> Vec<u8> {
 
 pub fn double_sha256(data: &[u8]) -> Vec<u8> {
     let first = Sha256::digest(data);
-    Sha256::digest(&first).to_vec()
+    Sha256::digest(first).to_vec()
+}
+
+/// Domain separation so a signed message can never pass as a transaction
+const MESSAGE_MAGIC: &[u8] = b"Blockchain Signed Message:\n";
+const PUBLIC_KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+fn message_hash(message: &str) -> Vec<u8> {
+    let mut data = MESSAGE_MAGIC.to_vec();
+    data.extend_from_slice(&(message.len() as u64).to_le_bytes());
+    data.extend_from_slice(message.as_bytes());
+    double_sha256(&data)
+}
+
+/// Checks a `sign-message` signature: it must be valid for `message` and
+/// its public key must hash to `address`. Fails on malformed input.
+pub fn verify_message(address: &str, signature: &str, message: &str) -> Result<bool> {
+    let pub_key_hash = decode_address(address)?;
+    let decoded = bs58::decode(signature.trim())
+        .with_alphabet(bs58::Alphabet::BITCOIN)
+        .into_vec()
+        .map_err(|e| anyhow!("Invalid signature encoding: {}", e))?;
+    if decoded.len() != PUBLIC_KEY_LEN + SIGNATURE_LEN {
+        return Err(anyhow!("Invalid signature length"));
+    }
+    
+    let (pub_key, sig) = decoded.split_at(PUBLIC_KEY_LEN);
+    if hash_pub_key(pub_key) != pub_key_hash {
+        return Ok(false);
+    }
+    Ok(UnparsedPublicKey::new(&ED25519, pub_key)
+        .verify(&message_hash(message), sig)
+        .is_ok())
+}
+
+/// Marks the current wallet file layout; files without it are a bare
+/// bincode map of address to key
+const WALLET_MAGIC: &[u8; 4] = b"BCWL";
+const WALLET_FORMAT_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+
+/// SLIP-10 master key tag for Ed25519
+const SLIP10_ED25519_SEED: &[u8] = b"ed25519 seed";
+const HARDENED: u32 = 0x8000_0000;
+const HD_PURPOSE: u32 = 44;
+const HD_COIN_TYPE: u32 = 1;
+/// Consecutive unused addresses after which a restore stops deriving
+pub const HD_GAP_LIMIT: u32 = 20;
+
+#[derive(Serialize, Deserialize)]
+struct WalletFile {
+    version: u32,
+    keys: KeyStore,
+    hd: Option<HdChain>,
+    /// Addresses tracked without a private key
+    watch_only: BTreeSet<String>,
+    /// Redeem scripts of tracked P2SH addresses, by address
+    scripts: BTreeMap<String, Script>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum KeyStore {
+    Plain(WalletSecrets),
+    Encrypted(EncryptedKeys),
+}
+
+/// Everything that must not be stored in the clear
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct WalletSecrets {
+    keys: HashMap<String, Wallet>,
+    mnemonic: Option<String>,
+}
+
+impl WalletSecrets {
+    fn upgrade_legacy_keys(&mut self) -> Result<()> {
+        for wallet in self.keys.values_mut() {
+            wallet.upgrade_legacy_key()?;
+        }
+        Ok(())
+    }
+}
+
+/// Private keys sealed with ChaCha20-Poly1305 under a key derived from the
+/// passphrase with Argon2id. Addresses stay readable so balances can be
+/// shown while the wallet is locked.
+#[derive(Serialize, Deserialize, Clone)]
+struct EncryptedKeys {
+    addresses: Vec<String>,
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+/// Position of the wallet on its derivation path
+/// m/44'/1'/account'/0'/index', all levels hardened as SLIP-10 requires
+#[derive(Serialize, Deserialize, Clone)]
+struct HdChain {
+    account: u32,
+    next_index: u32,
+    /// Derivation index of every address generated from the seed
+    indexes: BTreeMap<String, u32>,
 }
 
 #[derive(Default)]
 pub struct Wallets {
-    wallets: HashMap<String, Wallet>,
+    secrets: WalletSecrets,
+    encrypted: Option<EncryptedKeys>,
+    /// Encryption key while an encrypted wallet is unlocked
+    unlock_key: Option<[u8; 32]>,
+    hd: Option<HdChain>,
+    watch_only: BTreeSet<String>,
+    scripts: BTreeMap<String, Script>,
+    name: String,
+    path: PathBuf,
 }
 
 impl Wallets {
-    pub fn new() -> Result<Self> {
+    /// Loads the wallet called `name` from `data_dir`. The default wallet
+    /// starts out empty when its file is missing; named wallets must be
+    /// created first.
+    pub fn open(data_dir: &Path, name: &str) -> Result<Self> {
+        let path = wallet_path(data_dir, name)?;
+        if name != DEFAULT_WALLET && !path.exists() {
+            return Err(anyhow!("Wallet '{}' does not exist. Create it with new-wallet", name));
+        }
+        
         let mut wlt = Wallets {
-            wallets: HashMap::new(),
+            name: name.to_string(),
+            path,
+            ..Wallets::default()
         };
-        
         wlt.load_from_file()?;
         Ok(wlt)
     }
 
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
+    /// Creates an empty wallet called `name` in `data_dir`
+    pub fn create(data_dir: &Path, name: &str) -> Result<Self> {
+        let path = wallet_path(data_dir, name)?;
+        if path.exists() {
+            return Err(anyhow!("Wallet '{}' already exists", name));
+        }
+        
+        let wlt = Wallets {
+            name: name.to_string(),
+            path,
+            ..Wallets::default()
+        };
+        wlt.save_all()?;
+        Ok(wlt)
+    }
+
+    /// Copies an existing wallet file into the named wallets as `name`
+    pub fn load_file<P: AsRef<Path>>(data_dir: &Path, file: P, name: &str) -> Result<Self> {
+        let mut loaded = Wallets {
+            path: file.as_ref().to_path_buf(),
+            ..Wallets::default()
+        };
+        if !loaded.path.exists() {
+            return Err(anyhow!("Wallet file {} not found", loaded.path.display()));
+        }
+        loaded.load_from_file()?;
+        
+        let path = wallet_path(data_dir, name)?;
+        if path.exists() {
+            return Err(anyhow!("Wallet '{}' already exists", name));
+        }
+        loaded.name = name.to_string();
+        loaded.path = path;
+        loaded.save_all()?;
+        Ok(loaded)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Adds a new address. Keys come from the HD seed; a wallet without
+    /// one gets a freshly generated mnemonic, returned so it can be shown
+    /// to the user once.
+    pub fn create_wallet(&mut self) -> Result<(String, Option<String>)> {
+        if self.is_locked() {
+            return Err(anyhow!("Wallet is locked. Unlock it with --passphrase to add keys"));
+        }
+        
+        let new_mnemonic = if self.secrets.mnemonic.is_none() {
+            let mnemonic = generate_mnemonic()?;
+            self.set_mnemonic(&mnemonic, 0)?;
+            Some(mnemonic)
+        } else {
+            None
+        };
+        
+        let address = self.derive_next()?;
+        Ok((address, new_mnemonic))
+    }
+
+    /// Gives a wallet without an HD seed the one of `mnemonic` and
+    /// regenerates its addresses, deriving until `HD_GAP_LIMIT`
+    /// consecutive addresses are unused according to `is_used`. Returns
+    /// the restored addresses.
+    pub fn restore<F>(&mut self, mnemonic: &str, account: u32, is_used: F) -> Result<Vec<String>>
+    where
+        F: Fn(&str) -> bool,
+    {
+        if self.is_locked() {
+            return Err(anyhow!("Wallet is locked. Unlock it with --passphrase to restore keys"));
+        }
+        if self.hd.is_some() {
+            return Err(anyhow!(
+                "Wallet '{}' already has an HD seed, which restoring would replace. Create a wallet with new-wallet NAME and restore into it with --wallet NAME",
+                self.name
+            ));
+        }
+        self.set_mnemonic(mnemonic, account)?;
+        
+        let seed = self.seed()?;
+        let mut used_until = 0;
+        let mut index = 0;
+        while index < used_until + HD_GAP_LIMIT {
+            let address = derive_wallet(&seed, account, index).get_address();
+            if is_used(&address) {
+                used_until = index + 1;
+            }
+            index += 1;
+        }
+        
+        let mut restored = Vec::new();
+        for _ in 0..used_until.max(1) {
+            restored.push(self.derive_next()?);
+        }
+        Ok(restored)
+    }
+
+    fn set_mnemonic(&mut self, mnemonic: &str, account: u32) -> Result<()> {
+        let parsed = Mnemonic::parse(mnemonic)
+            .map_err(|e| anyhow!("Invalid mnemonic: {}", e))?;
+        if account >= HARDENED {
+            return Err(anyhow!("Account number {} is out of range", account));
+        }
+        
+        self.secrets.mnemonic = Some(parsed.to_string());
+        self.hd = Some(HdChain {
+            account,
+            next_index: 0,
+            indexes: BTreeMap::new(),
+        });
+        Ok(())
+    }
+
+    fn seed(&self) -> Result<[u8; 64]> {
+        let mnemonic = self.secrets.mnemonic.as_ref()
+            .ok_or_else(|| anyhow!("Wallet has no HD seed"))?;
+        let parsed = Mnemonic::parse(mnemonic)
+            .map_err(|e| anyhow!("Invalid mnemonic in wallet file: {}", e))?;
+        Ok(parsed.to_seed(""))
+    }
+
+    fn derive_next(&mut self) -> Result<String> {
+        let seed = self.seed()?;
+        let hd = self.hd.as_mut()
+            .ok_or_else(|| anyhow!("Wallet has no HD seed"))?;
+        
+        let wallet = derive_wallet(&seed, hd.account, hd.next_index);
         let address = wallet.get_address();
-        self.wallets.insert(address.clone(), wallet);
-        address
+        hd.indexes.insert(address.clone(), hd.next_index);
+        hd.next_index += 1;
+        self.watch_only.remove(&address);
+        self.secrets.keys.insert(address.clone(), wallet);
+        Ok(address)
     }
 
+    /// Derivation path of `address` if it was generated from the HD seed
+    pub fn hd_path(&self, address: &str) -> Option<String> {
+        let hd = self.hd.as_ref()?;
+        let index = hd.indexes.get(address)?;
+        Some(format!("m/{}'/{}'/{}'/0'/{}'", HD_PURPOSE, HD_COIN_TYPE, hd.account, index))
+    }
+
+    /// Every tracked address, including watch-only ones
     pub fn get_all_addresses(&self) -> Vec<String> {
-        self.wallets.keys().cloned().collect()
+        let mut addresses = match &self.encrypted {
+            Some(encrypted) if self.unlock_key.is_none() => encrypted.addresses.clone(),
+            _ => self.secrets.keys.keys().cloned().collect(),
+        };
+        addresses.extend(self.watch_only.iter().cloned());
+        addresses.extend(self.scripts.keys().cloned());
+        addresses
+    }
+
+    /// Records a redeem script so its P2SH address is tracked and its
+    /// inputs can be signed. Returns the address.
+    pub fn add_script(&mut self, redeem_script: &Script) -> Result<String> {
+        let address = encode_versioned_address(MULTISIG_ADDRESS_VERSION, &redeem_script.script_hash()?);
+        self.watch_only.remove(&address);
+        self.scripts.insert(address.clone(), redeem_script.clone());
+        Ok(address)
+    }
+
+    /// Redeem script of a P2SH address added with `add_script`
+    pub fn get_script(&self, address: &str) -> Option<&Script> {
+        self.scripts.get(address)
+    }
+
+    /// Public key of one of our addresses, which works while locked too
+    pub fn find_pub_key(&self, address: &str) -> Option<Vec<u8>> {
+        self.secrets.keys.get(address).map(|wallet| wallet.public_key())
+    }
+
+    /// Tracks `address` for balances and history without holding its key.
+    /// Returns false if it was already watched.
+    pub fn import_address(&mut self, address: &str) -> Result<bool> {
+        Script::from_address(address)?;
+        if self.get_all_addresses().iter().any(|a| a == address) && !self.is_watch_only(address) {
+            return Err(anyhow!("Wallet already holds the private key for {}", address));
+        }
+        Ok(self.watch_only.insert(address.to_string()))
+    }
+
+    pub fn is_watch_only(&self, address: &str) -> bool {
+        self.watch_only.contains(address)
+    }
+
+    /// Private key of `address` in WIF encoding
+    pub fn dump_privkey(&self, address: &str) -> Result<String> {
+        Ok(self.get_wallet(address)?.to_wif())
+    }
+
+    /// Adds a WIF private key. Returns its address and whether it was new.
+    pub fn import_privkey(&mut self, wif: &str) -> Result<(String, bool)> {
+        let wallet = Wallet::from_wif(wif)?;
+        let address = wallet.get_address();
+        Ok((address.clone(), self.add_key(wallet)?))
+    }
+
+    fn add_key(&mut self, wallet: Wallet) -> Result<bool> {
+        if self.is_locked() {
+            return Err(anyhow!("Wallet is locked. Unlock it with --passphrase to import keys"));
+        }
+        let address = wallet.get_address();
+        if self.secrets.keys.contains_key(&address) {
+            return Ok(false);
+        }
+        self.watch_only.remove(&address);
+        self.secrets.keys.insert(address, wallet);
+        Ok(true)
+    }
+
+    /// Writes every key, the HD seed and the watch-only addresses as text.
+    /// Lines are `mnemonic <words>`, `account <n>`, `key <wif> <address>
+    /// [path]`, `watch <address>`, `multisig <m> <hex keys>` and `script
+    /// <hex redeem script>`; `#` starts a comment.
+    pub fn export_dump(&self) -> Result<String> {
+        if self.is_locked() {
+            return Err(anyhow!("Wallet is locked. Unlock it with --passphrase to export keys"));
+        }
+        
+        let mut out = String::new();
+        out.push_str("# Wallet dump. Anyone with this file can spend the coins of these keys.\n");
+        if let (Some(mnemonic), Some(hd)) = (&self.secrets.mnemonic, &self.hd) {
+            out.push_str(&format!("mnemonic {}\n", mnemonic));
+            out.push_str(&format!("account {}\n", hd.account));
+        }
+        
+        let mut addresses: Vec<&String> = self.secrets.keys.keys().collect();
+        addresses.sort();
+        for address in addresses {
+            let wallet = &self.secrets.keys[address];
+            match self.hd_path(address) {
+                Some(path) => out.push_str(&format!("key {} {} {}\n", wallet.to_wif(), address, path)),
+                None => out.push_str(&format!("key {} {}\n", wallet.to_wif(), address)),
+            }
+        }
+        for address in &self.watch_only {
+            out.push_str(&format!("watch {}\n", address));
+        }
+        for script in self.scripts.values() {
+            match script.parse_multisig() {
+                Some((required, keys)) => {
+                    let keys: Vec<String> = keys.iter().map(hex::encode).collect();
+                    out.push_str(&format!("multisig {} {}\n", required, keys.join(" ")));
+                }
+                None => out.push_str(&format!("script {}\n", hex::encode(serialize(script)?))),
+            }
+        }
+        Ok(out)
+    }
+
+    /// Merges a dump written by `export_dump`. The dump's HD seed is
+    /// adopted only by a wallet that has none; otherwise its keys are kept
+    /// as imported keys. Returns the number of new addresses.
+    pub fn import_dump(&mut self, text: &str) -> Result<usize> {
+        if self.is_locked() {
+            return Err(anyhow!("Wallet is locked. Unlock it with --passphrase to import keys"));
+        }
+        
+        let mut mnemonic = None;
+        let mut account = 0;
+        let mut keys = Vec::new();
+        let mut watch = Vec::new();
+        let mut scripts = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (tag, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            let bad_line = || anyhow!("Invalid wallet dump at line {}", number + 1);
+            match tag {
+                "mnemonic" => mnemonic = Some(rest.trim().to_string()),
+                "account" => account = rest.trim().parse().map_err(|_| bad_line())?,
+                "key" => {
+                    let wif = fields.first().ok_or_else(bad_line)?;
+                    let wallet = Wallet::from_wif(wif)
+                        .map_err(|e| anyhow!("Line {}: {}", number + 1, e))?;
+                    if let Some(address) = fields.get(1) {
+                        if *address != wallet.get_address() {
+                            return Err(anyhow!("Line {}: key does not match address {}", number + 1, address));
+                        }
+                    }
+                    let index = fields.get(2).and_then(|path| parse_hd_index(path, account));
+                    keys.push((wallet, index));
+                }
+                "watch" => {
+                    let address = fields.first().ok_or_else(bad_line)?;
+                    Script::from_address(address)?;
+                    watch.push(address.to_string());
+                }
+                "multisig" => {
+                    let required = fields.first().and_then(|m| m.parse().ok()).ok_or_else(bad_line)?;
+                    let keys = fields[1..].iter()
+                        .map(|key| hex::decode(key).map_err(|_| bad_line()))
+                        .collect::<Result<Vec<_>>>()?;
+                    scripts.push(MultisigPolicy::new(required, keys)?.redeem_script());
+                }
+                "script" => {
+                    let data = fields.first().and_then(|data| hex::decode(data).ok()).ok_or_else(bad_line)?;
+                    scripts.push(deserialize(&data).map_err(|_| bad_line())?);
+                }
+                _ => return Err(bad_line()),
+            }
+        }
+        
+        let seed = match mnemonic {
+            Some(mnemonic) if self.secrets.mnemonic.is_none() => {
+                self.set_mnemonic(&mnemonic, account)?;
+                Some(self.seed()?)
+            }
+            _ => None,
+        };
+        
+        let mut added = 0;
+        for (wallet, index) in keys {
+            let address = wallet.get_address();
+            if self.add_key(wallet)? {
+                added += 1;
+            }
+            if let (Some(seed), Some(index), Some(hd)) = (&seed, index, self.hd.as_mut()) {
+                if derive_wallet(seed, account, index).get_address() == address {
+                    hd.indexes.insert(address, index);
+                    hd.next_index = hd.next_index.max(index + 1);
+                }
+            }
+        }
+        for address in watch {
+            if !self.secrets.keys.contains_key(&address) && self.watch_only.insert(address) {
+                added += 1;
+            }
+        }
+        for script in scripts {
+            if !self.scripts.values().any(|known| *known == script) {
+                self.add_script(&script)?;
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted.is_some()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.encrypted.is_some() && self.unlock_key.is_none()
+    }
+
+    /// Returns the key for `address` for signing, failing if the wallet
+    /// is locked or does not hold that address
+    pub fn get_wallet(&self, address: &str) -> Result<&Wallet> {
+        if self.is_watch_only(address) {
+            return Err(anyhow!("Address {} is watch-only and cannot sign", address));
+        }
+        if self.scripts.contains_key(address) {
+            return Err(anyhow!("Address {} is a script address without a key of its own", address));
+        }
+        if self.is_locked() {
+            if self.get_all_addresses().iter().any(|a| a == address) {
+                return Err(anyhow!("Wallet is locked. Unlock it with --passphrase to sign for {}", address));
+            }
+            return Err(anyhow!("Wallet not found"));
+        }
+        self.secrets.keys.get(address).ok_or_else(|| anyhow!("Wallet not found"))
+    }
+
+    /// Decrypts the private keys into memory
+    pub fn unlock(&mut self, passphrase: &str) -> Result<()> {
+        let encrypted = match &self.encrypted {
+            Some(encrypted) => encrypted,
+            None => return Err(anyhow!("Wallet is not encrypted")),
+        };
+        
+        let key = derive_key(passphrase, &encrypted.salt)?;
+        let mut data = encrypted.ciphertext.clone();
+        let plain = sealing_key(&key)?
+            .open_in_place(aead_nonce(&encrypted.nonce)?, Aad::from(WALLET_MAGIC), &mut data)
+            .map_err(|_| anyhow!("Incorrect wallet passphrase"))?;
+        
+        self.secrets = deserialize(plain)?;
+        self.unlock_key = Some(key);
+        Ok(())
+    }
+
+    /// Drops the decrypted private keys from memory
+    pub fn lock(&mut self) {
+        if self.encrypted.is_some() {
+            self.secrets = WalletSecrets::default();
+            self.unlock_key = None;
+        }
     }
 
-    pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
-        self.wallets.get(address)
+    /// Encrypts an unencrypted wallet. The wallet is left locked.
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        if self.encrypted.is_some() {
+            return Err(anyhow!("Wallet is already encrypted. Use change-passphrase instead"));
+        }
+        if passphrase.is_empty() {
+            return Err(anyhow!("Passphrase must not be empty"));
+        }
+        
+        self.encrypted = Some(self.seal(passphrase)?);
+        self.lock();
+        Ok(())
+    }
+
+    pub fn change_passphrase(&mut self, old: &str, new: &str) -> Result<()> {
+        if new.is_empty() {
+            return Err(anyhow!("Passphrase must not be empty"));
+        }
+        
+        self.unlock(old)?;
+        self.encrypted = Some(self.seal(new)?);
+        self.lock();
+        Ok(())
+    }
+
+    /// Encrypts the keys under a fresh salt derived from `passphrase`
+    fn seal(&mut self, passphrase: &str) -> Result<EncryptedKeys> {
+        let rng = SystemRandom::new();
+        let mut salt = vec![0u8; SALT_LEN];
+        rng.fill(&mut salt).map_err(|_| anyhow!("Failed to generate salt"))?;
+        
+        let key = derive_key(passphrase, &salt)?;
+        self.unlock_key = Some(key);
+        self.seal_with_key(&key, salt)
+    }
+
+    fn seal_with_key(&self, key: &[u8; 32], salt: Vec<u8>) -> Result<EncryptedKeys> {
+        let rng = SystemRandom::new();
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill(&mut nonce).map_err(|_| anyhow!("Failed to generate nonce"))?;
+        
+        let mut ciphertext = serialize(&self.secrets)?;
+        sealing_key(key)?
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(WALLET_MAGIC), &mut ciphertext)
+            .map_err(|_| anyhow!("Failed to encrypt wallet"))?;
+        
+        let mut addresses: Vec<String> = self.secrets.keys.keys().cloned().collect();
+        addresses.sort();
+        
+        Ok(EncryptedKeys {
+            addresses,
+            salt,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
     }
 
     fn load_from_file(&mut self) -> Result<()> {
-        if !Path::new(WALLET_FILE).exists() {
+        if !self.path.exists() {
             return Ok(());
         }
         
-        let data = std::fs::read(WALLET_FILE)?;
-        self.wallets = deserialize(&data)?;
+        let data = std::fs::read(&self.path)?;
+        if !data.starts_with(WALLET_MAGIC) {
+            self.secrets.keys = deserialize(&data)?;
+            return self.secrets.upgrade_legacy_keys();
+        }
+        
+        let data = &data[WALLET_MAGIC.len()..];
+        let version: u32 = deserialize(data)?;
+        if version != WALLET_FORMAT_VERSION {
+            return Err(anyhow!("Unsupported wallet file version {}", version));
+        }
+        let file: WalletFile = deserialize(data)?;
+        
+        match file.keys {
+            KeyStore::Plain(secrets) => self.secrets = secrets,
+            KeyStore::Encrypted(encrypted) => self.encrypted = Some(encrypted),
+        }
+        self.hd = file.hd;
+        self.watch_only = file.watch_only;
+        for script in file.scripts.into_values() {
+            self.add_script(&script)?;
+        }
         Ok(())
     }
 
     pub fn save_all(&self) -> Result<()> {
-        std::fs::create_dir_all("data")?;
-        let data = serialize(&self.wallets)?;
-        std::fs::write(WALLET_FILE, data)?;
-        Ok(())
+        let keys = match (&self.encrypted, &self.unlock_key) {
+            (None, _) => KeyStore::Plain(self.secrets.clone()),
+            (Some(encrypted), None) => KeyStore::Encrypted(encrypted.clone()),
+            (Some(encrypted), Some(key)) => {
+                KeyStore::Encrypted(self.seal_with_key(key, encrypted.salt.clone())?)
+            }
+        };
+        let file = WalletFile {
+            version: WALLET_FORMAT_VERSION,
+            keys,
+            hd: self.hd.clone(),
+            watch_only: self.watch_only.clone(),
+            scripts: self.scripts.clone(),
+        };
+        
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut data = WALLET_MAGIC.to_vec();
+        data.extend(serialize(&file)?);
+        write_private(&self.path, &data)
+    }
+}
+
+/// Writes `data` to `path` readable by the owner only, as wallet files
+/// and dumps hold private keys
+pub fn write_private<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to new files; tighten ones written earlier
+    #[cfg(unix)]
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+/// File in `data_dir` holding the wallet called `name`
+pub fn wallet_path(data_dir: &Path, name: &str) -> Result<PathBuf> {
+    if name == DEFAULT_WALLET {
+        return Ok(data_dir.join(WALLET_FILE));
+    }
+    let valid = !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(anyhow!("Invalid wallet name '{}': use letters, digits, '-' and '_'", name));
+    }
+    Ok(data_dir.join(WALLETS_DIR).join(format!("{}.wallet", name)))
+}
+
+/// Names of all wallets, the default one first
+pub fn list_wallets(data_dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(data_dir.join(WALLETS_DIR)) {
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "wallet") {
+                if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    names.push(stem.to_string());
+                }
+            }
+        }
+    }
+    names.sort();
+    names.insert(0, DEFAULT_WALLET.to_string());
+    Ok(names)
+}
+
+/// Wallet used when a command does not pass --wallet
+pub fn selected_wallet(data_dir: &Path) -> Result<String> {
+    match std::fs::read_to_string(data_dir.join(SELECTED_WALLET_FILE)) {
+        Ok(name) => Ok(name.trim().to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(DEFAULT_WALLET.to_string()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn select_wallet(data_dir: &Path, name: &str) -> Result<()> {
+    Wallets::open(data_dir, name)?;
+    std::fs::create_dir_all(data_dir)?;
+    std::fs::write(data_dir.join(SELECTED_WALLET_FILE), name)?;
+    Ok(())
+}
+
+/// Generates a 24-word BIP-39 mnemonic
+fn generate_mnemonic() -> Result<String> {
+    let mut entropy = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut entropy)
+        .map_err(|_| anyhow!("Failed to generate entropy"))?;
+    let mnemonic = Mnemonic::from_entropy(&entropy)
+        .map_err(|e| anyhow!("Failed to create mnemonic: {}", e))?;
+    Ok(mnemonic.to_string())
+}
+
+/// Index of an m/44'/1'/account'/0'/index' path written by `hd_path`
+fn parse_hd_index(path: &str, account: u32) -> Option<u32> {
+    let levels: Vec<u32> = path.strip_prefix("m/")?
+        .split('/')
+        .map(|level| level.strip_suffix('\'')?.parse().ok())
+        .collect::<Option<_>>()?;
+    match levels.as_slice() {
+        [HD_PURPOSE, HD_COIN_TYPE, a, 0, index] if *a == account => Some(*index),
+        _ => None,
+    }
+}
+
+/// Derives the key at m/44'/1'/account'/0'/index' from a BIP-39 seed
+fn derive_wallet(seed: &[u8], account: u32, index: u32) -> Wallet {
+    let path = [HD_PURPOSE, HD_COIN_TYPE, account, 0, index];
+    Wallet::from_seed(slip10_derive(seed, &path))
+}
+
+/// SLIP-10 Ed25519 derivation, where every level is hardened
+fn slip10_derive(seed: &[u8], path: &[u32]) -> [u8; 32] {
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, SLIP10_ED25519_SEED), seed);
+    let (mut key, mut chain_code) = split_hmac(tag.as_ref());
+    
+    for index in path {
+        let mut data = Vec::with_capacity(37);
+        data.push(0);
+        data.extend_from_slice(&key);
+        data.extend_from_slice(&(index | HARDENED).to_be_bytes());
+        
+        let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, &chain_code), &data);
+        (key, chain_code) = split_hmac(tag.as_ref());
     }
-}
\ No newline at end of file
+    
+    key
+}
+
+fn split_hmac(tag: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut key = [0u8; 32];
+    let mut chain_code = [0u8; 32];
+    key.copy_from_slice(&tag[..32]);
+    chain_code.copy_from_slice(&tag[32..]);
+    (key, chain_code)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+fn sealing_key(key: &[u8; 32]) -> Result<LessSafeKey> {
+    let key = UnboundKey::new(&CHACHA20_POLY1305, key)
+        .map_err(|_| anyhow!("Invalid encryption key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn aead_nonce(nonce: &[u8]) -> Result<Nonce> {
+    Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow!("Invalid wallet nonce"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restore_does_not_replace_an_existing_seed() {
+        let mnemonic = generate_mnemonic().unwrap();
+        let mut wallets = Wallets::default();
+        let restored = wallets.restore(&mnemonic, 0, |_| false).unwrap();
+        assert_eq!(restored.len(), 1);
+
+        let other = generate_mnemonic().unwrap();
+        assert!(wallets.restore(&other, 0, |_| false).is_err());
+        assert_eq!(wallets.get_all_addresses(), restored);
+    }
+
+    #[test]
+    fn encrypted_keys_unlock_with_the_current_passphrase_only() {
+        let mut wallets = Wallets::default();
+        let (address, _) = wallets.create_wallet().unwrap();
+        let key = wallets.get_wallet(&address).unwrap().clone();
+
+        wallets.encrypt("old").unwrap();
+        assert!(wallets.is_locked());
+        assert!(wallets.get_wallet(&address).is_err());
+        assert_eq!(wallets.get_all_addresses(), vec![address.clone()]);
+        assert!(wallets.encrypt("other").is_err());
+
+        let error = wallets.unlock("wrong").unwrap_err();
+        assert_eq!(error.to_string(), "Incorrect wallet passphrase");
+        wallets.unlock("old").unwrap();
+        assert_eq!(*wallets.get_wallet(&address).unwrap(), key);
+        wallets.lock();
+
+        assert!(wallets.change_passphrase("wrong", "new").is_err());
+        wallets.change_passphrase("old", "new").unwrap();
+        assert!(wallets.is_locked());
+        assert!(wallets.unlock("old").is_err());
+        wallets.unlock("new").unwrap();
+        assert_eq!(*wallets.get_wallet(&address).unwrap(), key);
+    }
+
+    #[test]
+    fn slip10_matches_the_published_ed25519_vectors() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let vectors = [
+            (&[][..], "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7", "a4b2856bfec510abab89753fac1ac0e1112364e7d250545963f135f2a33188ed"),
+            (&[0], "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3", "8c8a13df77a28f3445213a0f432fde644acaa215fc72dcdf300d5efaa85d350c"),
+            (&[0, 1], "b1d0bad404bf35da785a64ca1ac54b2617211d2777696fbffaf208f746ae84f2", "1932a5270f335bed617d5b935c80aedb1a35bd9fc1e31acafd5372c30f5c1187"),
+            (&[0, 1, 2], "92a5b23c0b8a99e37d07df3fb9966917f5d06e02ddbd909c7e184371463e9fc9", "ae98736566d30ed0e9d2f4486a64bc95740d89c7db33f52121f8ea8f76ff0fc1"),
+            (&[0, 1, 2, 2], "30d1dc7e5fc04c31219ab25a27ae00b50f6fd66622f6e9c913253d6511d1e662", "8abae2d66361c879b900d204ad2cc4984fa2aa344dd7ddc46007329ac76c429c"),
+            (&[0, 1, 2, 2, 1000000000], "8f94d394a8e8fd6b1bc2f3f49f5c47e385281d5c17e65324b0f62483e37e8793", "3c24da049451555d51a7014a37337aa4e12d41e485abccfa46b47dfb2af54b7a"),
+        ];
+        for (path, private_key, public_key) in vectors {
+            let key = slip10_derive(&seed, path);
+            assert_eq!(hex::encode(key), private_key);
+            assert_eq!(hex::encode(Wallet::from_seed(key).public_key()), public_key);
+        }
+    }
+
+    #[test]
+    fn wif_keys_round_trip() {
+        let wallet = Wallet::from_seed([7; SEED_LEN]);
+        assert_eq!(Wallet::from_wif(&wallet.to_wif()).unwrap(), wallet);
+
+        let wif = wallet.to_wif();
+        let last = if wif.ends_with('1') { "2" } else { "1" };
+        let tampered = format!("{}{}", &wif[..wif.len() - 1], last);
+        assert!(Wallet::from_wif(&tampered).is_err());
+        assert!(Wallet::from_wif(&base58check_encode(ADDRESS_VERSION, &[7; SEED_LEN])).is_err());
+        assert!(Wallet::from_wif(&base58check_encode(PRIVKEY_VERSION, &[7; 20])).is_err());
+    }
+
+    #[test]
+    fn wallet_dumps_round_trip() {
+        let mut wallets = Wallets::default();
+        let (first, _) = wallets.create_wallet().unwrap();
+        let (second, _) = wallets.create_wallet().unwrap();
+        let imported = Wallet::from_seed([1; SEED_LEN]);
+        wallets.import_privkey(&imported.to_wif()).unwrap();
+        let watched = Wallet::from_seed([2; SEED_LEN]).get_address();
+        wallets.import_address(&watched).unwrap();
+        let policy = MultisigPolicy::new(1, vec![imported.public_key(), Wallet::from_seed([3; SEED_LEN]).public_key()]).unwrap();
+        let multisig = wallets.add_script(&policy.redeem_script()).unwrap();
+
+        let dump = wallets.export_dump().unwrap();
+        let mut restored = Wallets::default();
+        assert_eq!(restored.import_dump(&dump).unwrap(), 5);
+        assert_eq!(restored.import_dump(&dump).unwrap(), 0);
+
+        let mut addresses = wallets.get_all_addresses();
+        addresses.sort();
+        let mut restored_addresses = restored.get_all_addresses();
+        restored_addresses.sort();
+        assert_eq!(restored_addresses, addresses);
+        for address in [&first, &second] {
+            assert_eq!(restored.hd_path(address), wallets.hd_path(address));
+        }
+        assert_eq!(restored.dump_privkey(&imported.get_address()).unwrap(), imported.to_wif());
+        assert!(restored.is_watch_only(&watched));
+        assert_eq!(restored.get_script(&multisig), wallets.get_script(&multisig));
+        assert_eq!(restored.create_wallet().unwrap().0, wallets.create_wallet().unwrap().0);
+    }
+}